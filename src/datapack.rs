@@ -0,0 +1,283 @@
+//! Export of songs as Minecraft datapacks that play them with `playsound`.
+//!
+//! `/function <namespace>:play` starts the song for the player running it and
+//! `/function <namespace>:stop` stops it. A score counts game ticks while it
+//! plays, every game tick with notes has its own function and a tree of
+//! functions finds the right one without checking every tick.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::parsers::Song;
+use crate::playback::{self, Schedule, ScheduledTick};
+use crate::schematic::INSTRUMENT_BLOCKS;
+
+/// Minecraft 1.20.1, the same version schematics are made for.
+pub const PACK_FORMAT: i32 = 15;
+
+/* how many ticks a function of the tree checks before splitting further */
+const TREE_LEAF: usize = 8;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatapackOptions {
+    /// Namespace of the functions, lowercase letters, digits, `_`, `-` and `.` only.
+    pub namespace: String,
+    /// Sound event for each custom instrument by name, like `"Piano": "custom:piano"`.
+    pub sounds: HashMap<String, String>,
+    /// Sound category the notes play in, players can turn it down on its own.
+    pub source: String,
+    /// How far panning moves a note to the side, in blocks.
+    pub pan_distance: f64,
+}
+
+impl Default for DatapackOptions {
+    fn default() -> Self {
+        DatapackOptions {
+            namespace: "nbs".to_owned(),
+            sounds: HashMap::new(),
+            source: "record".to_owned(),
+            pan_distance: 2_f64,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DatapackError {
+    Namespace(String),
+    /// A custom instrument that notes use but [`DatapackOptions::sounds`] doesn't map.
+    Instrument(String),
+    Io(PathBuf, std::io::Error),
+}
+
+impl fmt::Display for DatapackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatapackError::Namespace(namespace) => write!(f, "\"{}\" isn't a valid namespace, use lowercase letters, digits, _, - and .", namespace),
+            DatapackError::Instrument(name) => write!(f, "the custom instrument \"{}\" has no sound event configured", name),
+            DatapackError::Io(path, error) => write!(f, "couldn't write {}: {}", path.display(), error),
+        }
+    }
+}
+
+impl std::error::Error for DatapackError {}
+
+fn json_string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for character in text.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            character if character.is_control() => escaped.push_str(&format!("\\u{:04x}", character as u32)),
+            character => escaped.push(character),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/* the function that checks the game ticks first..last of the list, splitting in half until few are left */
+fn tree(files: &mut BTreeMap<String, String>, namespace: &str, game_ticks: &[u32], first: usize, last: usize) {
+    let mut function = String::new();
+    if last - first <= TREE_LEAF {
+        for game_tick in &game_ticks[first..last] {
+            function.push_str(&format!("execute if score @s {0}.time matches {1} run function {0}:ticks/{1}\n", namespace, game_tick));
+        }
+    } else {
+        let middle = (first + last) / 2;
+        for (from, to) in [(first, middle), (middle, last)] {
+            function.push_str(&format!("execute if score @s {0}.time matches {1}..{2} run function {0}:tree/{3}_{4}\n", namespace, game_ticks[from], game_ticks[to-1], from, to));
+            tree(files, namespace, game_ticks, from, to);
+        }
+    }
+    files.insert(format!("data/{}/functions/tree/{}_{}.mcfunction", namespace, first, last), function);
+}
+
+/* game ticks are 1/20 of a second */
+fn game_tick(time: f64) -> u32 {
+    (time * 20_f64).round() as u32
+}
+
+/// The files of the datapack by their path inside it.
+///
+/// Pitch outside of 0.5 to 2 (keys 33 to 57 on vanilla sounds) gets clamped by the game.
+pub fn export(song: &Song, options: &DatapackOptions) -> Result<BTreeMap<String, String>, DatapackError> {
+    let namespace = &options.namespace;
+    if namespace.is_empty() || !namespace.chars().all(|character| matches!(character, 'a'..='z' | '0'..='9' | '_' | '-' | '.')) {
+        return Err(DatapackError::Namespace(namespace.clone()));
+    }
+
+    let instruments = playback::instruments(song);
+    let vanilla_count = song.header.vanilla_instrument_count.max(0) as usize;
+    let mut sounds: Vec<Option<String>> = Vec::new();
+    for (index, instrument) in instruments.iter().enumerate() {
+        sounds.push(if index < vanilla_count {
+            INSTRUMENT_BLOCKS.get(index).map(|(name, _)| format!("minecraft:block.note_block.{}", name))
+        } else {
+            options.sounds.get(&instrument.name).cloned()
+        });
+    }
+
+    //the loop section is the song's second pass, so tempo changers before the loop start sound right
+    let looping = song.header.looping != 0;
+    let mut first_pass = Schedule::with_loops(song, Some(0));
+    let first_pass_ticks = first_pass.by_ref().count();
+    let loop_time = game_tick(first_pass.end_time());
+    let mut schedule = Schedule::with_loops(song, Some(if looping { 1 } else { 0 }));
+    let ticks: Vec<ScheduledTick> = schedule.by_ref().collect();
+    let end_time = game_tick(schedule.end_time());
+    let ticks = if looping { &ticks[..] } else { &ticks[..first_pass_ticks] };
+
+    let mut functions: BTreeMap<u32, String> = BTreeMap::new();
+    for tick in ticks {
+        for note in &tick.notes {
+            if note.volume <= 0_f32 {
+                continue;
+            }
+            let Some(sound) = &sounds[note.instrument] else {
+                return Err(DatapackError::Instrument(instruments[note.instrument].name.clone()));
+            };
+            //^ is to the player's left, panning goes from left to right
+            let offset = (-note.panning as f64 * options.pan_distance * 100_f64).round() / 100_f64;
+            let offset = if offset == 0_f64 { String::new() } else { offset.to_string() };
+            functions.entry(game_tick(tick.time)).or_default().push_str(&format!(
+                "playsound {} {} @s ^{} ^ ^ {} {}\n",
+                sound,
+                options.source,
+                offset,
+                (note.volume as f64 * 1000_f64).round() / 1000_f64,
+                (note.speed as f64 * 10000_f64).round() / 10000_f64,
+            ));
+        }
+    }
+
+    let mut files: BTreeMap<String, String> = BTreeMap::new();
+    let description = if song.header.author.is_empty() { song.header.name.clone() } else { format!("{} by {}", song.header.name, song.header.author) };
+    files.insert("pack.mcmeta".to_owned(), format!("{{\n  \"pack\": {{\n    \"pack_format\": {},\n    \"description\": {}\n  }}\n}}\n", PACK_FORMAT, json_string(&description)));
+    files.insert("data/minecraft/tags/functions/load.json".to_owned(), format!("{{\n  \"values\": [\"{}:load\"]\n}}\n", namespace));
+    files.insert("data/minecraft/tags/functions/tick.json".to_owned(), format!("{{\n  \"values\": [\"{}:tick\"]\n}}\n", namespace));
+
+    let function = |name: &str| format!("data/{}/functions/{}.mcfunction", namespace, name);
+    files.insert(function("load"), format!("scoreboard objectives add {0}.time dummy\nscoreboard objectives add {0}.loops dummy\n", namespace));
+    files.insert(function("tick"), format!("execute as @a[tag={0}.playing] at @s run function {0}:step\n", namespace));
+    //forever is -1, which never counts down to 0
+    let loops = match playback::header_loops(song) {
+        Some(loops) => loops as i64,
+        None => -1,
+    };
+    files.insert(function("play"), format!("scoreboard players set @s {0}.time 0\nscoreboard players set @s {0}.loops {1}\ntag @s add {0}.playing\n", namespace, loops));
+    files.insert(function("stop"), format!("tag @s remove {0}.playing\n", namespace));
+    files.insert(function("end"), if looping {
+        format!("execute if score @s {0}.loops matches 0 run function {0}:stop\nscoreboard players remove @s[tag={0}.playing,scores={{{0}.loops=1..}}] {0}.loops 1\nscoreboard players set @s[tag={0}.playing] {0}.time {1}\n", namespace, loop_time)
+    } else {
+        format!("function {0}:stop\n", namespace)
+    });
+
+    let game_ticks: Vec<u32> = functions.keys().copied().collect();
+    let mut step = format!("execute if score @s {0}.time matches {1}.. run function {0}:end\n", namespace, end_time);
+    if !game_ticks.is_empty() {
+        step.push_str(&format!("function {}:tree/0_{}\n", namespace, game_ticks.len()));
+        tree(&mut files, namespace, &game_ticks, 0, game_ticks.len());
+    }
+    step.push_str(&format!("scoreboard players add @s {}.time 1\n", namespace));
+    files.insert(function("step"), step);
+    for (game_tick, commands) in functions {
+        files.insert(function(&format!("ticks/{}", game_tick)), commands);
+    }
+    Ok(files)
+}
+
+/// Writes the datapack's files into `folder`, which can go straight into a world's `datapacks` folder.
+pub fn write(folder: &Path, files: &BTreeMap<String, String>) -> Result<(), DatapackError> {
+    for (path, contents) in files {
+        let path = folder.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|error| DatapackError::Io(parent.to_owned(), error))?;
+        }
+        fs::write(&path, contents).map_err(|error| DatapackError::Io(path.clone(), error))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::{tests::empty_song, Instrument, Noteblock};
+
+    fn harp() -> Noteblock {
+        Noteblock { instrument: 0, key: 45, volume: 100, panning: 100, pitch: 0 }
+    }
+
+    /* a note on each of the song ticks, at 10 ticks per second they're 2 game ticks apart */
+    fn song_with_ticks(ticks: std::ops::Range<i32>) -> Song {
+        let mut song = empty_song(1);
+        for tick in ticks {
+            song.noteblocks.insert(tick, 0, harp());
+        }
+        song
+    }
+
+    #[test]
+    fn namespaces_are_checked() {
+        let song = song_with_ticks(0..1);
+        for namespace in ["", "Song", "my song", "nbs:song"] {
+            let options = DatapackOptions { namespace: namespace.to_owned(), ..DatapackOptions::default() };
+            assert!(matches!(export(&song, &options), Err(DatapackError::Namespace(name)) if name == namespace));
+        }
+        let options = DatapackOptions { namespace: "my_song-2.0".to_owned(), ..DatapackOptions::default() };
+        assert!(export(&song, &options).unwrap().contains_key("data/my_song-2.0/functions/play.mcfunction"));
+    }
+
+    #[test]
+    fn custom_instruments_need_a_sound_event() {
+        let mut song = song_with_ticks(0..1);
+        song.custom_instruments.push(Instrument { name: "Piano".to_owned(), sound_file: "piano.ogg".to_owned(), sound_key: 45, press_key: 0 });
+        song.noteblocks.insert(1, 0, Noteblock { instrument: 16, ..harp() });
+        let mut options = DatapackOptions::default();
+        assert!(matches!(export(&song, &options), Err(DatapackError::Instrument(name)) if name == "Piano"));
+
+        options.sounds.insert("Piano".to_owned(), "custom:piano".to_owned());
+        let files = export(&song, &options).unwrap();
+        assert_eq!(files["data/nbs/functions/ticks/2.mcfunction"], "playsound custom:piano record @s ^ ^ ^ 1 1\n");
+    }
+
+    #[test]
+    fn few_ticks_are_one_leaf() {
+        let files = export(&song_with_ticks(0..3), &DatapackOptions::default()).unwrap();
+        assert!(files["data/nbs/functions/step.mcfunction"].contains("function nbs:tree/0_3\n"));
+        assert_eq!(files["data/nbs/functions/tree/0_3.mcfunction"], concat!(
+            "execute if score @s nbs.time matches 0 run function nbs:ticks/0\n",
+            "execute if score @s nbs.time matches 2 run function nbs:ticks/2\n",
+            "execute if score @s nbs.time matches 4 run function nbs:ticks/4\n",
+        ));
+        for game_tick in [0, 2, 4] {
+            assert_eq!(files[&format!("data/nbs/functions/ticks/{}.mcfunction", game_tick)], "playsound minecraft:block.note_block.harp record @s ^ ^ ^ 1 1\n");
+        }
+        assert_eq!(files.keys().filter(|path| path.contains("/tree/") || path.contains("/ticks/")).count(), 4);
+    }
+
+    #[test]
+    fn many_ticks_split_the_tree() {
+        let files = export(&song_with_ticks(0..10), &DatapackOptions::default()).unwrap();
+        assert!(files["data/nbs/functions/step.mcfunction"].contains("function nbs:tree/0_10\n"));
+        assert_eq!(files["data/nbs/functions/tree/0_10.mcfunction"], concat!(
+            "execute if score @s nbs.time matches 0..8 run function nbs:tree/0_5\n",
+            "execute if score @s nbs.time matches 10..18 run function nbs:tree/5_10\n",
+        ));
+        assert!(files["data/nbs/functions/tree/0_5.mcfunction"].ends_with("execute if score @s nbs.time matches 8 run function nbs:ticks/8\n"));
+        assert!(files["data/nbs/functions/tree/5_10.mcfunction"].starts_with("execute if score @s nbs.time matches 10 run function nbs:ticks/10\n"));
+        assert_eq!(files.keys().filter(|path| path.contains("/ticks/")).count(), 10);
+    }
+
+    #[test]
+    fn panning_and_volume_go_into_playsound() {
+        let mut song = empty_song(1);
+        song.noteblocks.insert(0, 0, Noteblock { panning: 0, volume: 50, ..harp() });
+        song.noteblocks.insert(1, 0, Noteblock { volume: 0, ..harp() });
+        let files = export(&song, &DatapackOptions::default()).unwrap();
+        assert_eq!(files["data/nbs/functions/ticks/0.mcfunction"], "playsound minecraft:block.note_block.harp record @s ^2 ^ ^ 0.5 1\n");
+        //silent notes leave no function behind
+        assert!(!files.contains_key("data/nbs/functions/ticks/2.mcfunction"));
+    }
+}
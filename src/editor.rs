@@ -1,25 +1,23 @@
-use crossterm::event::{KeyCode, KeyEvent, MouseEvent, KeyModifiers, self, Event};
-use rodio::source::Buffered;
-use rodio::{OutputStream, Decoder, Source};
+use crossterm::event::{KeyCode, KeyModifiers, self, Event};
 use ratatui::backend::CrosstermBackend;
 use ratatui::layout::Rect;
 use crate::noteblock_widget::{NoteblockWidget};
-use crate::parsers::{Song, song, self, Layer, Instrument, NoteblockSection, Header, Noteblock};
+use crate::file_browser::{FileBrowser, FileBrowserWidget};
+use crate::library_view::{LibraryView, LibraryWidget};
+use nbs_tui::parsers::Song;
+use nbs_tui::files::{self, LoadOptions};
+use nbs_tui::playback::{MixerEvent, PlayState, Player};
+use nbs_tui::playlist::{self, Playlist, Repeat, Transition};
 use crossterm::event::{DisableMouseCapture, EnableMouseCapture};
 use crossterm::terminal::{EnterAlternateScreen, LeaveAlternateScreen, self};
-use std::fs::File;
-use std::io::Read;
-use std::ops::{AddAssign, Add, Div};
-use std::sync::mpsc::{Sender, Receiver};
-use std::sync::{mpsc, Arc, Mutex};
-use std::thread::JoinHandle;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
-use std::{io, error, thread};
+use std::{io, error};
 use ratatui::Terminal;
 use ratatui::{
     backend::Backend,
     style::{Color, Style},
-    widgets::{Block, BorderType, Borders, Paragraph},
+    widgets::Paragraph,
     Frame,
 };
 
@@ -59,24 +57,40 @@ impl<B: Backend> Tui<B> {
     /// [`Draw`]: tui::Terminal::draw
     /// [`rendering`]: crate::ui:render
     pub fn draw(&mut self, editor_state: &mut EditorState) -> AppResult<()> {
-        if editor_state.song.is_some(){
-            let block =
-                NoteblockWidget {
-                    block_width: 4,
-                    block_height: 2
-                };
-            // if ((editor_state.tick - editor_state.cmp_tick).abs() * block.block_width as f32).floor() < 1_f32 { //if no difference in render, dont
-            //     return Ok(());
-            // }
-            
-            // editor_state.cmp_tick = editor_state.tick;
-            self.terminal.draw(|frame: &mut Frame<'_, B>| {
-
-                    // Render into the first chunk of the layout.
+        let block =
+            NoteblockWidget {
+                block_width: 4,
+                block_height: 2
+            };
+        self.terminal.draw(|frame: &mut Frame<'_, B>| {
+
+                // Render into the first chunk of the layout.
+                if editor_state.song.is_some() {
                     frame.render_stateful_widget(block,frame.size(),editor_state); //we need faster rendering
-                
-            })?;
-        }
+                }
+                if let Some(message) = &editor_state.message {
+                    let area = frame.size();
+                    let line = Rect::new(area.x, area.bottom().saturating_sub(1), area.width, area.height.min(1));
+                    frame.render_widget(Paragraph::new(message.as_str()).style(Style::default().fg(Color::Red)), line);
+                } else if editor_state.song.is_some() {
+                    let area = frame.size();
+                    let line = Rect::new(area.x, area.bottom().saturating_sub(1), area.width, area.height.min(1));
+                    frame.render_widget(Paragraph::new(transport_status(editor_state)).style(Style::default().fg(Color::DarkGray)), line);
+                }
+                if let Some(library) = &mut editor_state.library {
+                    let area = frame.size();
+                    let popup = Rect::new(area.x + area.width / 20, area.y + area.height / 10, area.width - area.width / 10, area.height - area.height / 5);
+                    let playlist = &editor_state.playlist;
+                    let queued = playlist.len() - playlist.position().map_or(0, |position| position + 1);
+                    frame.render_stateful_widget(LibraryWidget { queued }, popup, library);
+                }
+                if let Some(browser) = &mut editor_state.browser {
+                    let area = frame.size();
+                    let popup = Rect::new(area.x + area.width / 10, area.y + area.height / 10, area.width - area.width / 5, area.height - area.height / 5);
+                    frame.render_stateful_widget(FileBrowserWidget, popup, browser);
+                }
+            
+        })?;
         Ok(())
     }
 
@@ -91,174 +105,261 @@ impl<B: Backend> Tui<B> {
     }
 }
 
-/*
-noteblock, tick (default -1), layer, (default -1), index (-1 if didnt find) */
-fn get_next_noteblock(noteblocks: &Vec<NoteblockSection>, start_index: i32) -> (Noteblock,i32,i32,i32) {
-    let mut tick = -1;
-    let mut layer = -1;
-    for index in (start_index as usize)..noteblocks.len() {
-        match &noteblocks[index] {
-            NoteblockSection::SetTick(num) => {
-                tick = *num;
-            },
-            NoteblockSection::SetLayer(num) => {
-                layer = *num;
-            },
-            NoteblockSection::Noteblock(noteblock) => {
-                return (noteblock.clone(),tick,layer,index as i32);
-            },
-        }
+/* how long the crossfade setting fades for */
+const CROSSFADE_SECONDS: f64 = 3_f64;
+
+
+
+/// Handles the tick event of the terminal: the playhead goes wherever the player is,
+/// so it follows tempo changers and loops the way they sound.
+fn tick(editor_state: &mut EditorState, player: &Player) {
+    if editor_state.transport == PlayState::Stopped {
+        return;
+    }
+    let status = player.status();
+    if status.id == Some(editor_state.current) {
+        editor_state.tick = status.position as f32;
+        editor_state.prev_tick = status.position.floor() as i32;
     }
-    return (Noteblock {
-        instrument: -1,
-        key: -1,
-        volume: -1,
-        panning: u8::MAX,
-        pitch: -1,
-    },tick,layer,-1);
 }
-#[derive(Clone, Debug, PartialEq, Eq)]
-enum SongEdit {
-    Header(Header),
-    Layer(Layer,u16),
-    Instrument(Instrument,u32),
-    Noteblock(NoteblockSection,u16),
-    Song(Option<Song>)
+
+
+#[derive(Debug)]
+pub struct EditorState {
+    pub song: Option<Song>,
+    pub transport: PlayState,
+    /// The playhead, a fractional tick.
+    pub tick: f32,
+    /// First tick drawn, the one the playhead is in.
+    pub prev_tick: i32,
+    pub message: Option<String>,
+    /// Tick playback starts from when asked to play from the cursor.
+    pub cursor: i32,
+    /// Open while picking a song to load.
+    pub browser: Option<FileBrowser>,
+    /// Where the browser opens next time.
+    pub folder: PathBuf,
+    pub library: Option<LibraryView>,
+    pub playlist: Playlist,
+    pub transition: Transition,
+    /// Numbers the songs given to the player, so its events can be matched to them.
+    pub songs_sent: usize,
+    /// Number of the song playing.
+    pub current: usize,
+    /// The song the player has queued, and which song of the playlist to move on to with it.
+    pub queued: Option<(usize, Song, Option<usize>)>,
+    /// How songs are read, from the command line.
+    pub load_options: LoadOptions,
 }
 
+/* problems go in the message line */
+fn load_song(editor_state: &mut EditorState, location: &Path) -> Option<Song> {
+    match files::load_with(location, &editor_state.load_options) {
+        Ok(loaded) => {
+            if let Some(warning) = loaded.warning {
+                editor_state.message = Some(format!("{}: {}", location.display(), warning));
+            }
+            Some(loaded.song)
+        },
+        Err(error) => {
+            editor_state.message = Some(format!("Couldn't load {}", error));
+            None
+        }
+    }
+}
 
+/* shows the song from its start */
+fn show_song(editor_state: &mut EditorState, song: Song) {
+    editor_state.song = Some(song);
+    editor_state.cursor = 0;
+    editor_state.tick = 0_f32;
+    editor_state.prev_tick = 0;
+}
 
-//todo traverse multiple ticks in a single tick if needed (work at lower gui tick speed)
-/// Handles the tick event of the terminal.
-fn tick(editor_state: &mut EditorState) {
-    if editor_state.playing {
-        // println!("|tick|");
-        // if(editor_state.index==-1){ //we need to start from beginning then
-            
-        // }
-        let prev_instant = editor_state.prev_instant.elapsed();
-        
-        if editor_state.prev_tick<editor_state.next_tick {
-            editor_state.tick = editor_state.prev_tick as f32+
-                ((((editor_state.next_tick-editor_state.prev_tick) as f32)*(prev_instant.as_micros() as f32/editor_state.wait_duration.as_micros() as f32)));
+/* loads the song and starts playing it, then queues whatever the playlist has next */
+fn open_song(editor_state: &mut EditorState, player: &Player, location: &Path) -> bool {
+    editor_state.message = None;
+    let Some(song) = load_song(editor_state, location) else {
+        return false;
+    };
+    if let Some(folder) = location.parent().filter(|folder| !folder.as_os_str().is_empty()) {
+        editor_state.folder = folder.to_owned();
+    }
+    editor_state.songs_sent += 1;
+    editor_state.current = editor_state.songs_sent;
+    let track = player.load(song.clone());
+    if editor_state.message.is_none() {
+        editor_state.message = track.warning.clone(); //after the file's own warning
+    }
+    player.play(editor_state.current, track);
+    editor_state.transport = PlayState::Playing;
+    show_song(editor_state, song);
+    queue_upcoming(editor_state, player);
+    true
+}
 
+/* queues the song the playlist plays next, called whenever that could have changed.
+songs that don't load are passed over, their message stays up */
+fn queue_upcoming(editor_state: &mut EditorState, player: &Player) {
+    let mut ahead = editor_state.playlist.clone();
+    let mut next = None;
+    for _ in 0..ahead.len() {
+        let Some(path) = ahead.advance().map(Path::to_owned) else {
+            break;
+        };
+        if let Some(song) = load_song(editor_state, &path) {
+            next = Some((song, ahead.current_index()));
+            break;
         }
-        if prev_instant < editor_state.wait_duration {
-            return;
-        }
-        
-        editor_state.prev_index = editor_state.next_index;
-        editor_state.prev_tick = editor_state.next_tick;
-        editor_state.prev_instant.add_assign(editor_state.wait_duration);
-
-        // let mut tick = get_tick(&editor_state.song.as_mut().unwrap().noteblocks, editor_state.prev_index);
-        editor_state.next_index = find_next_tick_index(&editor_state.song.as_mut().unwrap().noteblocks, editor_state.prev_index);
-        // let mut new_duration = Duration::from(get_tick(found_index));
-        if editor_state.next_index==-1 {
-            // println!("found index was {}, from a start index of {}, the tick was {}, and is now {}",found_index,editor_state.index,tick,get_next_loop_tick(tick));
-            // tick = get_next_loop_tick(tick);
-            let start_tick = editor_state.song.as_ref().unwrap().header.loop_start_tick as i32;
-            editor_state.next_index = find_next_index_tick(&editor_state.song.as_mut().unwrap().noteblocks,start_tick);
-            // editor_state.next_index = find_next_tick_index(&editor_state.song.as_mut().unwrap().noteblocks, -1);
-            // if get_tick(&editor_state.song.as_mut().unwrap().noteblocks,editor_state.next_index)!= editor_state.song.as_mut().unwrap().header.loop_start_tick {
-            // }
-        }
-        
-        editor_state.next_tick = get_tick(&editor_state.song.as_mut().unwrap().noteblocks,editor_state.next_index);
-        if editor_state.prev_tick>editor_state.next_tick {
-            // println!("loop so its ({:?}-{:?})+{:?}",get_next_loop_tick(editor_state.prev_tick),editor_state.prev_tick,editor_state.next_tick);
-            editor_state.wait_duration = Duration::from_micros((1000000_f64/(editor_state.tempo)) as u64).mul_f64(((get_next_loop_tick(editor_state.prev_tick)-editor_state.prev_tick)+(editor_state.next_tick-editor_state.song.as_ref().unwrap().header.loop_start_tick as i32)) as f64);
-        }else{
-            editor_state.wait_duration = Duration::from_micros((1000000_f64/(editor_state.tempo)) as u64).mul_f64((editor_state.next_tick-editor_state.prev_tick) as f64);
-        }
-        // println!("the tick is {}, last tick is {}",tick,editor_state.last_tick);
-
     }
-    //assume index is the waiting for tick
-    
+    editor_state.songs_sent += 1;
+    let id = editor_state.songs_sent;
+    player.queue(next.as_ref().map(|(song, _)| (id, player.load(song.clone()))));
+    editor_state.queued = next.map(|(song, index)| (id, song, index));
 }
-fn get_next_loop_tick(tick: i32) -> i32{
-    return (((tick+1) as f64/16_f64).ceil()*16_f64) as i32;
+
+/* moves the playlist somewhere else and hands over to that song with the transition */
+fn skip_to(editor_state: &mut EditorState, player: &Player, path: Option<PathBuf>) {
+    let Some(path) = path else {
+        return;
+    };
+    if editor_state.transport == PlayState::Stopped {
+        open_song(editor_state, player, &path);
+        return;
+    }
+    let Some(song) = load_song(editor_state, &path) else {
+        return;
+    };
+    editor_state.songs_sent += 1;
+    player.queue(Some((editor_state.songs_sent, player.load(song.clone()))));
+    player.skip();
+    editor_state.queued = Some((editor_state.songs_sent, song, None));
 }
 
+/* plays a song of the playlist, adding it first when it isn't in there */
+fn play_in_playlist(editor_state: &mut EditorState, player: &Player, path: PathBuf) {
+    let index = match editor_state.playlist.songs.iter().position(|song| *song == path) {
+        Some(index) => index,
+        None => {
+            editor_state.playlist.push(path);
+            editor_state.playlist.len() - 1
+        },
+    };
+    if let Some(path) = editor_state.playlist.select(index).map(Path::to_owned) {
+        open_song(editor_state, player, &path);
+    }
+}
 
-fn get_tick(noteblocks: &Vec<NoteblockSection>, index: i32) -> i32 {
-    if index == -1 {
-        return 0;
+/* songs and playlists both open from the browser and the command line */
+fn open_path(editor_state: &mut EditorState, player: &Player, path: PathBuf) {
+    if !playlist::is_playlist(&path) {
+        play_in_playlist(editor_state, player, path);
+        return;
     }
-    match noteblocks[index as usize] {
-        NoteblockSection::SetTick(current_tick) => {
-            return current_tick;
+    match Playlist::load(&path) {
+        Ok(mut loaded) => {
+            loaded.repeat = editor_state.playlist.repeat;
+            loaded.set_shuffle(editor_state.playlist.shuffle());
+            editor_state.playlist = loaded;
+            if editor_state.playlist.is_empty() {
+                editor_state.message = Some(format!("{} has no songs", path.display()));
+            }
+            for _ in 0..editor_state.playlist.len() {
+                match editor_state.playlist.advance().map(Path::to_owned) {
+                    Some(song) if !open_song(editor_state, player, &song) => continue,
+                    _ => break,
+                }
+            }
         },
-        NoteblockSection::SetLayer(_) => {},
-        NoteblockSection::Noteblock(_) => {},
+        Err(error) => editor_state.message = Some(format!("Couldn't load {}", error)),
     }
-    todo!();
 }
 
-fn find_next_tick_index(noteblocks: &Vec<NoteblockSection>, last_index: i32) -> i32 {
-    let mut found_index: i32 = -1;
-    // let mut found_tick: i32 = -1;
-    for i in ((last_index+1) as usize)..noteblocks.len() {
-        match noteblocks[i]{
-            NoteblockSection::SetTick(_) => {
-                found_index = i as i32;
-                // found_tick=tick;
-                break;
-            },
-            NoteblockSection::SetLayer(_) => {},
-            NoteblockSection::Noteblock(_) => {},
-        }
+/* plays the shown song from the tick, starting it over if it had stopped */
+fn play_from(editor_state: &mut EditorState, player: &Player, from: i32) {
+    let Some(song) = editor_state.song.clone() else {
+        return;
+    };
+    if player.status().id != Some(editor_state.current) {
+        editor_state.songs_sent += 1;
+        editor_state.current = editor_state.songs_sent;
+        player.play(editor_state.current, player.load(song));
+        editor_state.transport = PlayState::Playing;
+        queue_upcoming(editor_state, player);
     }
-    // if found_index==-1&&last_index!=-1 {
-    //     return find_next_tick_index(noteblocks,-1);
-    // }
-
-    return found_index;
+    player.seek(from);
+    if editor_state.transport == PlayState::Paused {
+        player.resume();
+        editor_state.transport = PlayState::Playing;
+    }
+    tick(editor_state, player);
 }
 
-fn find_next_index_tick(noteblocks: &Vec<NoteblockSection>, tick: i32) -> i32 {
-    let mut found_index: i32 = -1;
-    // let mut found_tick: i32 = -1;
-    for i in 0..noteblocks.len() {
-        match noteblocks[i]{
-            NoteblockSection::SetTick(num) => {
-                if num >= tick {
-                    found_index = i as i32;
-                    break;
-                }
-            },
-            NoteblockSection::SetLayer(_) => {},
-            NoteblockSection::Noteblock(_) => {},
-        }
+/* space: pauses, resumes, or plays the shown song from its start once it stopped */
+fn toggle_pause(editor_state: &mut EditorState, player: &Player) {
+    match editor_state.transport {
+        PlayState::Playing => {
+            player.pause();
+            editor_state.transport = PlayState::Paused;
+        },
+        PlayState::Paused => {
+            player.resume();
+            editor_state.transport = PlayState::Playing;
+        },
+        PlayState::Stopped => play_from(editor_state, player, 0),
     }
-    // if found_index==-1&&last_index!=-1 {
-    //     return find_next_tick_index(noteblocks,-1);
-    // }
+}
 
-    return found_index;
+/* cuts off the sound and goes back to the start of the song */
+fn stop(editor_state: &mut EditorState, player: &Player) {
+    player.stop();
+    editor_state.transport = PlayState::Stopped;
+    editor_state.queued = None;
+    editor_state.tick = 0_f32;
+    editor_state.prev_tick = 0;
 }
 
-#[derive(Debug)]
-pub struct EditorState {
-    pub song: Option<Song>,
-    pub playing: bool,
-    pub tempo: f64,
-    pub cmp_tick: f32,
-    pub tick: f32,
-    pub prev_tick: i32,
-    pub next_tick: i32,
-    pub prev_index: i32,
-    pub next_index: i32,
-    pub prev_instant: Instant,
-    pub wait_duration: Duration,
-    pub debug_instant: Instant,
+/* the view follows the cursor while nothing plays */
+fn move_cursor(editor_state: &mut EditorState, offset: i32) {
+    editor_state.cursor = editor_state.cursor.saturating_add(offset).max(0);
+    if editor_state.transport == PlayState::Stopped {
+        editor_state.prev_tick = (editor_state.cursor - 4).max(0);
+        editor_state.tick = editor_state.prev_tick as f32;
+    }
+}
+
+fn transport_status(editor_state: &EditorState) -> String {
+    let mut status = vec![match editor_state.transport {
+        PlayState::Playing => "playing",
+        PlayState::Paused => "paused",
+        PlayState::Stopped => "stopped",
+    }.to_owned()];
+    if let Some(song) = &editor_state.song {
+        status.push(if song.header.name.is_empty() { "(untitled)".to_owned() } else { song.header.name.clone() });
+    }
+    let playlist = &editor_state.playlist;
+    if let Some(position) = playlist.position() {
+        status.push(format!("{}/{}", position + 1, playlist.len()));
+    }
+    if playlist.shuffle() {
+        status.push("shuffle".to_owned());
+    }
+    match playlist.repeat {
+        Repeat::Off => {},
+        Repeat::One => status.push("repeat one".to_owned()),
+        Repeat::All => status.push("repeat all".to_owned()),
+    }
+    match editor_state.transition {
+        Transition::Gapless => status.push("gapless".to_owned()),
+        Transition::Crossfade(seconds) => status.push(format!("crossfade {}s", seconds)),
+    }
+    status.join("  ")
 }
 
 
 
 /* this is blocking */
-pub fn start() -> AppResult<()> {
+pub fn start(file: Option<PathBuf>, load_options: LoadOptions) -> AppResult<()> {
     println!("GO");
     // Create an application.
     // thread::scope(|scope| {
@@ -267,31 +368,7 @@ pub fn start() -> AppResult<()> {
     let mut running = true;
     // song
 
-    let (
-        tx ,
-        rx ) = mpsc::channel();
-
-    
-
-    
-    thread::spawn(move || {
-        loop {
-            let song_edit: SongEdit = rx.recv().unwrap();
-            // println!("got a {:?}",song_edit);
-            match song_edit {
-                SongEdit::Header(_) => todo!(),
-                SongEdit::Layer(_, _) => todo!(),
-                SongEdit::Instrument(_, _) => todo!(),
-                SongEdit::Noteblock(_, _) => todo!(),
-                SongEdit::Song(newSong) => {
-                    if(newSong.is_some()){
-                        start_playing_sound(newSong.unwrap(),&rx);
-                    }
-                },
-            }
-            
-        }
-    });
+    let player = Player::new();
 
 
     // Initialize the terminal user interface.
@@ -303,18 +380,25 @@ pub fn start() -> AppResult<()> {
     let mut editor_state = EditorState {
         song:None,
         prev_tick:0,
-        next_index:0,
-        tempo:-1_f64,
-        prev_instant:Instant::now(),
-        playing: false,
-        debug_instant: Instant::now(),
-        next_tick: 0,
-        prev_index: 0,
+        transport: PlayState::Stopped,
         tick: 0_f32,
-        cmp_tick: 0_f32,
-        wait_duration: Duration::new(0,0),
+        message: None,
+        cursor: 0,
+        browser: None,
+        folder: PathBuf::from("."),
+        library: None,
+        playlist: Playlist::default(),
+        transition: Transition::default(),
+        songs_sent: 0,
+        current: 0,
+        queued: None,
+        load_options,
     };
 
+    if let Some(file) = file {
+        open_path(&mut editor_state, &player, file);
+    }
+
     let event_wait = Duration::from_secs(0);
     let wait_duration = Duration::from_millis(16);
     let mut last_tick = Instant::now();
@@ -324,6 +408,89 @@ pub fn start() -> AppResult<()> {
 
         if event::poll(event_wait).expect("no events available") {
             match event::read().expect("unable to read event") {
+                Event::Key(key_event) if editor_state.library.as_ref().is_some_and(|library| library.searching) => {
+                    let library = editor_state.library.as_mut().unwrap();
+                    match key_event.code {
+                        KeyCode::Enter => library.searching = false,
+                        KeyCode::Esc => {
+                            library.searching = false;
+                            library.search.clear();
+                        }
+                        KeyCode::Backspace => {
+                            library.search.pop();
+                        }
+                        KeyCode::Char(character) => library.search.push(character),
+                        _ => {}
+                    }
+                    library.refresh();
+                },
+                Event::Key(key_event) if editor_state.library.is_some() => {
+                    let library = editor_state.library.as_mut().unwrap();
+                    match key_event.code {
+                        KeyCode::Esc | KeyCode::Char('q') => editor_state.library = None,
+                        KeyCode::Char('/') => library.searching = true,
+                        KeyCode::Up => library.move_selection(-1),
+                        KeyCode::Down => library.move_selection(1),
+                        KeyCode::PageUp => library.move_selection(-10),
+                        KeyCode::PageDown => library.move_selection(10),
+                        KeyCode::Home => library.select(0),
+                        KeyCode::End => library.select(usize::MAX),
+                        KeyCode::Char('s') => library.next_sort(),
+                        KeyCode::Char('d') => {
+                            library.descending = !library.descending;
+                            library.refresh();
+                        }
+                        KeyCode::Char('r') => library.rescan(),
+                        KeyCode::Char('c') => {
+                            editor_state.playlist.clear();
+                            queue_upcoming(&mut editor_state, &player);
+                        }
+                        KeyCode::Char('a') => {
+                            if let Some(path) = library.selected().map(|entry| entry.path.clone()) {
+                                editor_state.playlist.push(path);
+                                if editor_state.transport != PlayState::Stopped {
+                                    queue_upcoming(&mut editor_state, &player);
+                                } else {
+                                    let last = editor_state.playlist.len() - 1;
+                                    if let Some(path) = editor_state.playlist.select(last).map(Path::to_owned) {
+                                        open_song(&mut editor_state, &player, &path);
+                                    }
+                                }
+                            }
+                        }
+                        KeyCode::Enter => {
+                            if let Some(path) = library.selected().map(|entry| entry.path.clone()) {
+                                editor_state.library = None;
+                                play_in_playlist(&mut editor_state, &player, path);
+                            }
+                        }
+                        _ => {}
+                    }
+                },
+                Event::Key(key_event) if editor_state.browser.is_some() => {
+                    let browser = editor_state.browser.as_mut().unwrap();
+                    match key_event.code {
+                        KeyCode::Esc | KeyCode::Char('q') => {
+                            editor_state.folder = browser.folder.clone();
+                            editor_state.browser = None;
+                        }
+                        KeyCode::Up => browser.move_selection(-1),
+                        KeyCode::Down => browser.move_selection(1),
+                        KeyCode::PageUp => browser.move_selection(-10),
+                        KeyCode::PageDown => browser.move_selection(10),
+                        KeyCode::Home => browser.select(0),
+                        KeyCode::End => browser.select(usize::MAX),
+                        KeyCode::Backspace | KeyCode::Left => browser.up(),
+                        KeyCode::Enter | KeyCode::Right => {
+                            if let Some(path) = browser.enter() {
+                                editor_state.folder = browser.folder.clone();
+                                editor_state.browser = None;
+                                open_path(&mut editor_state, &player, path);
+                            }
+                        }
+                        _ => {}
+                    }
+                },
                 Event::Key(key_event) => 
                     match key_event.code {
                         // Exit application on `ESC` or `q`
@@ -338,17 +505,60 @@ pub fn start() -> AppResult<()> {
                         }
                         // Counter handlers
                         KeyCode::Char('L') => {
-                            let location = "Nyan Cat.nbs";
-                            let mut f = File::open(format!("songs/{}",location)).unwrap();
-                            let mut buffer = vec!();
-                            f.read_to_end(&mut buffer).unwrap();
-                            let (_, temp) = parsers::song(&buffer).unwrap();
-                            editor_state.tempo = temp.header.tempo as f64 / 100_f64;
-                            editor_state.song = Some(temp);
-                            editor_state.playing=true;
-                            editor_state.prev_instant = Instant::now();
-                            editor_state.debug_instant = Instant::now();
-                            tx.send(SongEdit::Song(editor_state.song.clone())).unwrap();
+                            editor_state.browser = Some(FileBrowser::new(&editor_state.folder));
+                        }
+                        KeyCode::Char('B') => {
+                            editor_state.library = Some(LibraryView::open());
+                        }
+                        KeyCode::Left => move_cursor(&mut editor_state, -1),
+                        KeyCode::Right => move_cursor(&mut editor_state, 1),
+                        KeyCode::PageUp => move_cursor(&mut editor_state, -16),
+                        KeyCode::PageDown => move_cursor(&mut editor_state, 16),
+                        KeyCode::Home => move_cursor(&mut editor_state, i32::MIN),
+                        KeyCode::Char(' ') => toggle_pause(&mut editor_state, &player),
+                        KeyCode::Char('S') => stop(&mut editor_state, &player),
+                        KeyCode::Enter => {
+                            let cursor = editor_state.cursor;
+                            play_from(&mut editor_state, &player, cursor);
+                        }
+                        KeyCode::Char(',') | KeyCode::Char('.') => {
+                            let status = player.status();
+                            if status.id == Some(editor_state.current) {
+                                let offset = if key_event.code == KeyCode::Char('.') { 5_f64 } else { -5_f64 };
+                                player.seek_time((status.seconds + offset).max(0_f64));
+                                tick(&mut editor_state, &player);
+                            }
+                        }
+                        KeyCode::Char('n') => {
+                            let next = editor_state.playlist.skip().map(Path::to_owned);
+                            skip_to(&mut editor_state, &player, next);
+                        }
+                        KeyCode::Char('p') => {
+                            let previous = editor_state.playlist.previous().map(Path::to_owned);
+                            skip_to(&mut editor_state, &player, previous);
+                        }
+                        KeyCode::Char('s') => {
+                            let shuffle = !editor_state.playlist.shuffle();
+                            editor_state.playlist.set_shuffle(shuffle);
+                            queue_upcoming(&mut editor_state, &player);
+                        }
+                        KeyCode::Char('r') => {
+                            editor_state.playlist.repeat = editor_state.playlist.repeat.next();
+                            queue_upcoming(&mut editor_state, &player);
+                        }
+                        KeyCode::Char('x') => {
+                            editor_state.transition = match editor_state.transition {
+                                Transition::Gapless => Transition::Crossfade(CROSSFADE_SECONDS),
+                                Transition::Crossfade(_) => Transition::Gapless,
+                            };
+                            player.set_transition(editor_state.transition);
+                        }
+                        KeyCode::Char('W') => {
+                            let path = editor_state.folder.join("playlist.m3u");
+                            editor_state.message = Some(match editor_state.playlist.save(&path) {
+                                Ok(()) => format!("Saved the playlist to {}", path.display()),
+                                Err(error) => format!("Couldn't save {}", error),
+                            });
                         }
                         // KeyCode::Char('T') => {
                         //     tx.send("imposter");
@@ -366,9 +576,25 @@ pub fn start() -> AppResult<()> {
             }
         }
 
+        for event in player.take_events() {
+            match event {
+                MixerEvent::Finished(id) if id == editor_state.current => editor_state.transport = PlayState::Stopped,
+                MixerEvent::Started(id) if editor_state.queued.as_ref().is_some_and(|(queued, _, _)| *queued == id) => {
+                    let (_, song, index) = editor_state.queued.take().unwrap();
+                    if let Some(index) = index {
+                        editor_state.playlist.select(index);
+                    }
+                    editor_state.current = id;
+                    show_song(&mut editor_state, song);
+                    queue_upcoming(&mut editor_state, &player);
+                },
+                _ => {},
+            }
+        }
+
         // Render the user interface.
         tui.draw(&mut editor_state).unwrap();
-        tick(&mut editor_state);
+        tick(&mut editor_state, &player);
         std::thread::sleep(wait_duration.saturating_sub(last_tick.elapsed()));
         last_tick=Instant::now();
     }
@@ -379,226 +605,3 @@ pub fn start() -> AppResult<()> {
 
     Ok(())
 }
-const DEFAULT_INSTRUMENTS: [&str; 16] = ["harp","dbass","bdrum","sdrum","click","guitar","flute","bell","icechime","xylobone","iron_xylophone","cow_bell","didgeridoo","bit","banjo","pling"];
-
-fn start_playing_sound(mut song: Song, reciever: &Receiver<SongEdit>){
-
-    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-    let mut sounds: Vec<Buffered<Decoder<File>>> = Vec::new();
-    let mut total_instruments: Vec<Instrument> = Vec::new();
-    let mut effective_layers: Vec<Layer> = Vec::new();
-    // let guard: MutexGuard<'_, Option<Song>> = mutex_song.lock().unwrap();
-    // let song_option : Option<Song> = *guard;
-    // let binding = mutex_song.lock();
-    // let song : &Song = binding.as_ref().unwrap().as_ref().unwrap();
-    for name in DEFAULT_INSTRUMENTS {
-        // println!("Instrument {:?}",name);
-        sounds.push(Decoder::new(std::fs::File::open(format!("sounds/{}.ogg",name)).unwrap()).unwrap().buffered());
-        total_instruments.push(Instrument {
-            name: name.into(),
-            sound_file: name.into(),
-            sound_key: 45,
-            press_key: 1
-        });
-    };
-
-    let mut tempo_changer_index: i8 = -1;
-    for instrument in song.custom_instruments {
-        // println!("Custom instrument {:?} at sounds/{:?}",instrument.name,instrument.sound_file);
-        if instrument.name == "Tempo Changer" {
-            tempo_changer_index = sounds.len() as i8;
-            sounds.push(Decoder::new(std::fs::File::open("sounds/harp.ogg").unwrap()).unwrap().buffered()); //dummy sound because i dont wanna option
-            total_instruments.push(instrument);
-            continue;
-        }
-        sounds.push(Decoder::new(std::fs::File::open(format!("sounds/{}",instrument.sound_file)).unwrap()).unwrap().buffered());
-        total_instruments.push(instrument);
-    }
-
-    if !song.layers.is_empty() {
-        for layer in song.layers{
-            effective_layers.push(layer);
-        }
-    } else {
-        for i in 0..song.header.layer_count {
-            effective_layers.push(Layer {
-                name: format!("default_layer_{}",i),
-                locked: 0,
-                volume: 100,
-                stereo: 100
-            });
-        }
-    }
-    
-
-
-
-    let mut loop_count = 0;
-    // println!("tempo is {:?}tps",(song.header.tempo as f64 / 100_f64));
-
-
-    
-    let mut tick_length : f64;
-    let mut tick_duration : Duration;
-    let mut tick: i32=0;
-    let mut last_tick: i32 = 0;
-    let unaccuracy: Duration;
-    if std::env::consts::OS == "windows" {
-        unaccuracy = Duration::new(u64::MAX, 0); // never trust
-    }else{
-        unaccuracy = Duration::new(0, 5*1000*1000); // 5 ms untrustworthyness
-    }
-    // drop(song);
-    loop {
-        tick_length = (100000000_f64/(song.header.tempo as f64)) as f64;
-        tick_duration = std::time::Duration::from_micros(tick_length as u64);
-
-
-        let mut layer_pos: i32=-1;
-
-        let mut mixer: (std::sync::Arc<rodio::dynamic_mixer::DynamicMixerController<f32>>, rodio::dynamic_mixer::DynamicMixer<f32>) = rodio::dynamic_mixer::mixer(2,44100);
-        // let mut found = false;
-        let mut index: usize=usize::MAX;
-        for i in 0..song.noteblocks.len(){
-            match &song.noteblocks[i]{
-                NoteblockSection::SetTick(check) => 
-                    if check>=&tick {
-                        last_tick = tick;
-                        tick = *check;
-                        index=i;
-                        break;
-                    }
-                ,
-                NoteblockSection::SetLayer(_) => {},
-                NoteblockSection::Noteblock(_) => {},
-            }
-        }
-        if index==usize::MAX{
-            println!("Couldn't find starting position for {}",tick);
-            break;
-        } else {
-            // println!("beginning tick {:?} at time {:?}",tick,std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis());
-            // std::thread::sleep(tick_duration.mul_f64((tick-last_tick) as f64));
-        }
-
-        let mut lastTime = Instant::now();
-        let mut drift: u128 = 0;
-        let mut new_tick = -1;
-        let start_time = Instant::now();
-        loop {
-            
-            for i in index..song.noteblocks.len() {
-                // let section = 
-                // println!("doing section {:?}",section);
-                index=i;
-                match &song.noteblocks[i]{
-                    parsers::NoteblockSection::SetTick(num) => {
-                        new_tick = *num;
-                        break;
-                    }
-                    parsers::NoteblockSection::SetLayer(num) => layer_pos=i32::from(*num),
-                    parsers::NoteblockSection::Noteblock(noteblock) => {
-                        if noteblock.instrument == tempo_changer_index {
-                            tick_length = (1000000_f64/(noteblock.pitch as f64 / 15_f64)) as f64;
-                            tick_duration = std::time::Duration::from_micros(tick_length as u64);
-                            // println!("Set tempo to {}",(noteblock.pitch as f64 / 15_f64));
-                            continue;
-                        }
-                        mixer.0.add(
-                            sounds[noteblock.instrument as usize].clone()
-                            .speed(
-                                (2_f64.powf(
-                                    (
-                                        (
-                                            (noteblock.key as f64)-(total_instruments[noteblock.instrument as usize].sound_key as f64)
-                                        )+(
-                                            (noteblock.pitch as f64)/100_f64
-                                        )
-                                    )*(1_f64/12_f64))) as f32
-                            )
-                            
-                            .amplify((noteblock.volume as f32 * effective_layers[layer_pos as usize].volume as f32)/10000_f32)
-                            .convert_samples()
-                        );
-                        // println!("noteblock at {:?},{:?}: {:?}", tick,layer_pos, noteblock);
-        
-                    }
-                }
-            }
-            // print!("waiting from {last_tick} to {tick}, time is {:?} then",start_time.elapsed());
-            let duration = tick_duration.mul_f64((tick-last_tick) as f64);
-            if !duration.is_zero(){
-                if duration > unaccuracy {
-                    let mut rcv_iter = reciever.try_iter();
-                    let mut rcv = rcv_iter.next();
-                    while rcv.is_some() {
-                        match rcv.unwrap() {
-                            SongEdit::Header(_) => todo!(),
-                            SongEdit::Layer(_, _) => todo!(),
-                            SongEdit::Instrument(_, _) => todo!(),
-                            SongEdit::Noteblock(section, indx) => {
-                                song.noteblocks.insert(indx as usize, section);
-                                if indx as usize <= index {
-                                    index+=1;
-                                }
-                            },
-                            SongEdit::Song(_) => todo!(),
-                        }
-                        rcv = rcv_iter.next();
-                    }
-                    std::thread::sleep(duration.saturating_sub(unaccuracy))
-                }
-                while lastTime.elapsed()<duration { std::hint::spin_loop(); } //accurate waiting
-            }
-            // println!("{:?}, the duration was {:?}",start_time.elapsed(),duration);
-            drift+=lastTime.elapsed().as_nanos()-duration.as_nanos();
-            last_tick=tick;
-            tick=new_tick;
-            // lastTime = Instant::now();
-            lastTime.add_assign(duration);
-            stream_handle.play_raw(mixer.1.convert_samples()).unwrap();
-            
-            mixer = rodio::dynamic_mixer::mixer(2,44100);
-            index+=1;
-            if index==song.noteblocks.len(){
-                break;
-            }
-
-        }
-        // stream_handle.play_raw(mixer.1.convert_samples()).unwrap();
-        // mixer = rodio::dynamic_mixer::mixer(2,44100);
-        // //play last tick
-        // let duration = tick_duration.mul_f64((tick-last_tick) as f64);
-        // if duration > unaccuracy {
-        //     std::thread::sleep(duration.saturating_sub(unaccuracy))
-        // }
-        // while lastTime.elapsed()<duration { std::hint::spin_loop(); } //accurate waiting
-
-        // drift+=lastTime.elapsed().as_nanos()-duration.as_nanos();
-        // lastTime.add_assign(duration);
-
-        // stream_handle.play_raw(mixer.1.convert_samples()).unwrap();
-
-
-        //wait til beat
-        // println!("time since start1: {:?}",start_time.elapsed());
-        // println!("next loop tick is {}, while im at {}",get_next_loop_tick(tick),tick);
-        let duration = tick_duration.mul_f64((get_next_loop_tick(tick)-tick) as f64); //loops at the beat
-        if duration > unaccuracy {
-            std::thread::sleep(duration.saturating_sub(unaccuracy))
-        }
-        while lastTime.elapsed()<duration { std::hint::spin_loop(); } //accurate waiting
-        lastTime.add_assign(duration);
-
-        // println!("Drift was {:?}ms",(drift as f64)/1000000_f64);
-        if song.header.looping==0 {break;}
-        tick=song.header.loop_start_tick as i32;
-        loop_count+=1;
-        if loop_count == song.header.loop_count { break; }
-        // println!("time since start2: {:?}",start_time.elapsed());
-
-        // println!("====================================================LOOPED====================================================");
-    }
-
-    // std::thread::sleep(std::time::Duration::from_millis(1000));
-}
\ No newline at end of file
@@ -0,0 +1,215 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use nbs_tui::files::{self, Format};
+use nbs_tui::parsers::Header;
+use nbs_tui::playlist::{self, Playlist};
+use ratatui::{
+    buffer::Buffer,
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, StatefulWidget, Widget, Wrap},
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub name: String,
+    pub folder: bool,
+}
+
+/// What the browser shows about the selected file.
+#[derive(Debug)]
+pub enum Preview {
+    Song(Header),
+    Playlist(Playlist),
+    Error(String),
+}
+
+/// Folder listing with the songs and playlists that can be opened, and a preview of the selected one.
+#[derive(Debug)]
+pub struct FileBrowser {
+    pub folder: PathBuf,
+    pub entries: Vec<Entry>,
+    pub list_state: ListState,
+    pub preview: Option<Preview>,
+    pub error: Option<String>,
+}
+
+/* folders first, then songs and playlists, hidden ones left out */
+fn read_folder(folder: &Path) -> std::io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    if let Some(parent) = folder.parent() {
+        entries.push(Entry { path: parent.to_owned(), name: "..".to_owned(), folder: true });
+    }
+    let mut listed = Vec::new();
+    for entry in fs::read_dir(folder)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        let folder = path.is_dir();
+        if folder || Format::of(&path).is_some_and(|format| format.loadable()) || playlist::is_playlist(&path) {
+            listed.push(Entry { path, name, folder });
+        }
+    }
+    listed.sort_by_key(|entry| (!entry.folder, entry.name.to_lowercase()));
+    entries.extend(listed);
+    Ok(entries)
+}
+
+impl FileBrowser {
+    pub fn new(folder: &Path) -> FileBrowser {
+        let mut browser = FileBrowser {
+            folder: PathBuf::new(),
+            entries: Vec::new(),
+            list_state: ListState::default(),
+            preview: None,
+            error: None,
+        };
+        browser.open_folder(folder);
+        browser
+    }
+
+    /// Lists another folder, keeping the current listing if it can't be read.
+    pub fn open_folder(&mut self, folder: &Path) {
+        let folder = folder.canonicalize().unwrap_or_else(|_| folder.to_owned());
+        match read_folder(&folder) {
+            Ok(entries) => {
+                let previous = std::mem::replace(&mut self.folder, folder);
+                self.entries = entries;
+                self.error = None;
+                //going up selects the folder we came from
+                let selected = self.entries.iter().position(|entry| entry.name != ".." && entry.path == previous);
+                self.select(selected.unwrap_or(0));
+            },
+            Err(error) => self.error = Some(format!("Couldn't open {}: {}", folder.display(), error)),
+        }
+    }
+
+    pub fn selected(&self) -> Option<&Entry> {
+        self.entries.get(self.list_state.selected()?)
+    }
+
+    pub fn select(&mut self, index: usize) {
+        if self.entries.is_empty() {
+            self.list_state.select(None);
+            self.preview = None;
+            return;
+        }
+        self.list_state.select(Some(index.min(self.entries.len() - 1)));
+        self.preview = match self.selected() {
+            Some(entry) if entry.folder => None,
+            Some(entry) if playlist::is_playlist(&entry.path) => Some(match Playlist::load(&entry.path) {
+                Ok(playlist) => Preview::Playlist(playlist),
+                Err(error) => Preview::Error(error.to_string()),
+            }),
+            Some(entry) => Some(match files::header(&entry.path) {
+                Ok(header) => Preview::Song(header),
+                Err(error) => Preview::Error(error.to_string()),
+            }),
+            None => None,
+        };
+    }
+
+    /// Moves the selection by `offset` entries, stopping at either end.
+    pub fn move_selection(&mut self, offset: isize) {
+        let selected = self.list_state.selected().unwrap_or(0);
+        self.select(selected.saturating_add_signed(offset));
+    }
+
+    /// Opens the selected folder, or returns the selected song or playlist.
+    pub fn enter(&mut self) -> Option<PathBuf> {
+        let entry = self.selected()?.clone();
+        if entry.folder {
+            self.open_folder(&entry.path);
+            return None;
+        }
+        Some(entry.path)
+    }
+
+    pub fn up(&mut self) {
+        if let Some(parent) = self.folder.parent().map(Path::to_owned) {
+            self.open_folder(&parent);
+        }
+    }
+}
+
+fn playlist_lines(playlist: &Playlist) -> Vec<String> {
+    let mut lines = vec![format!("playlist of {} songs", playlist.len()), String::new()];
+    lines.extend(playlist.songs.iter().map(|song| {
+        song.file_name().map_or_else(|| song.display().to_string(), |name| name.to_string_lossy().into_owned())
+    }));
+    lines
+}
+
+fn preview_lines(header: &Header) -> Vec<String> {
+    let mut lines = vec![
+        format!("name: {}", if header.name.is_empty() { "(untitled)" } else { &header.name }),
+        format!("author: {}", header.author),
+    ];
+    if !header.orig_author.is_empty() {
+        lines.push(format!("original author: {}", header.orig_author));
+    }
+    let tempo = header.tempo as f64 / 100_f64;
+    let seconds = if tempo > 0_f64 { header.song_length as f64 / tempo } else { 0_f64 };
+    lines.push(format!("length: {} ticks, {}", header.song_length, crate::minutes(seconds)));
+    lines.push(format!("tempo: {:.2} ticks per second", tempo));
+    lines.push(format!("layers: {}", header.layer_count));
+    if !header.description.is_empty() {
+        lines.push(String::new());
+        lines.push(header.description.clone());
+    }
+    lines
+}
+
+#[derive(Debug)]
+pub struct FileBrowserWidget;
+
+impl StatefulWidget for FileBrowserWidget {
+    type State = FileBrowser;
+
+    fn render(self, area: Rect, buf: &mut Buffer, browser: &mut FileBrowser) {
+        if area.area() == 0 {
+            return;
+        }
+        Clear.render(area, buf);
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title(format!(" Open {} ", browser.folder.display()));
+        let inner = block.inner(area);
+        block.render(area, buf);
+
+        let chunks = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .split(inner);
+
+        let items: Vec<ListItem> = browser.entries.iter().map(|entry| {
+            if entry.folder {
+                ListItem::new(format!("{}/", entry.name)).style(Style::default().fg(Color::Blue))
+            } else {
+                ListItem::new(entry.name.as_str())
+            }
+        }).collect();
+        let list = List::new(items)
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .highlight_symbol("> ");
+        StatefulWidget::render(list, chunks[0], buf, &mut browser.list_state);
+
+        let (text, style) = match (&browser.error, &browser.preview) {
+            (Some(error), _) => (error.clone(), Style::default().fg(Color::Red)),
+            (None, Some(Preview::Song(header))) => (preview_lines(header).join("\n"), Style::default()),
+            (None, Some(Preview::Playlist(playlist))) => (playlist_lines(playlist).join("\n"), Style::default()),
+            (None, Some(Preview::Error(error))) => (error.clone(), Style::default().fg(Color::Red)),
+            (None, None) => ("Enter opens, Backspace goes up, Esc closes".to_owned(), Style::default().fg(Color::DarkGray)),
+        };
+        Paragraph::new(text)
+            .style(style)
+            .wrap(Wrap { trim: false })
+            .block(Block::default().borders(Borders::LEFT))
+            .render(chunks[1], buf);
+    }
+}
@@ -0,0 +1,203 @@
+//! Loading and saving songs in every format the crate knows, picked by file extension.
+
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::parsers::{self, Header, Song};
+use crate::{datapack, midi, render, schematic, text};
+
+/// What a file holds, from its extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Nbs,
+    Text,
+    #[cfg(feature = "serde")]
+    Json,
+    Midi,
+    Schematic,
+    Wav,
+    /// A folder, only for saving.
+    Datapack,
+}
+
+impl Format {
+    /// The format of a path, folders (existing ones or paths ending in a separator) are datapacks.
+    pub fn of(path: &Path) -> Option<Format> {
+        if path.is_dir() || path.as_os_str().to_string_lossy().ends_with(std::path::is_separator) {
+            return Some(Format::Datapack);
+        }
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "nbs" => Some(Format::Nbs),
+            text::EXTENSION => Some(Format::Text),
+            #[cfg(feature = "serde")]
+            "json" => Some(Format::Json),
+            "mid" | "midi" => Some(Format::Midi),
+            "schem" => Some(Format::Schematic),
+            "wav" => Some(Format::Wav),
+            _ => None,
+        }
+    }
+
+    /// Whether songs can be loaded from this format, the rest are export only.
+    pub fn loadable(self) -> bool {
+        match self {
+            Format::Nbs | Format::Text | Format::Midi => true,
+            #[cfg(feature = "serde")]
+            Format::Json => true,
+            Format::Schematic | Format::Wav | Format::Datapack => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FileError {
+    Io(PathBuf, std::io::Error),
+    /// An extension no format uses, or one that can't be loaded.
+    Format(PathBuf),
+    Invalid(PathBuf, Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileError::Io(path, error) => write!(f, "{}: {}", path.display(), error),
+            FileError::Format(path) => write!(f, "{}: unknown format, songs load from .nbs, .{}, .mid{} and save to those, .schem, .wav or a datapack folder", path.display(), text::EXTENSION, if cfg!(feature = "serde") { ", .json" } else { "" }),
+            FileError::Invalid(path, error) => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl std::error::Error for FileError {}
+
+/// A loaded song and what didn't make it into it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Loaded {
+    pub song: Song,
+    /// Set when a damaged file was recovered or imported notes don't fit Minecraft.
+    pub warning: Option<String>,
+}
+
+/// How [`load_with`] reads songs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoadOptions {
+    /// Loads damaged `.nbs` files as far as they go, saying what was lost in [`Loaded::warning`],
+    /// instead of failing.
+    pub recover: bool,
+    /// How MIDI files become songs, their tempo among other things.
+    pub midi: midi::ImportOptions,
+}
+
+/// How [`save_with`] writes songs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SaveOptions {
+    /// For datapack folders, the sound events of custom instruments among other things.
+    pub datapack: datapack::DatapackOptions,
+}
+
+fn invalid(path: &Path, error: impl std::error::Error + Send + Sync + 'static) -> FileError {
+    FileError::Invalid(path.to_owned(), Box::new(error))
+}
+
+fn utf8<'a>(path: &Path, buffer: &'a [u8]) -> Result<&'a str, FileError> {
+    std::str::from_utf8(buffer).map_err(|error| invalid(path, error))
+}
+
+/// Loads a song, damaged files are an error.
+pub fn load(path: &Path) -> Result<Loaded, FileError> {
+    load_with(path, &LoadOptions::default())
+}
+
+/// [`load`] with options.
+pub fn load_with(path: &Path, options: &LoadOptions) -> Result<Loaded, FileError> {
+    let format = Format::of(path).filter(|format| format.loadable()).ok_or_else(|| FileError::Format(path.to_owned()))?;
+    let buffer = fs::read(path).map_err(|error| FileError::Io(path.to_owned(), error))?;
+    match format {
+        Format::Midi => {
+            let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+            let import = midi::import_file(&buffer, file_name, &options.midi).map_err(|error| invalid(path, error))?;
+            let warning = if import.out_of_range.is_empty() {
+                None
+            } else {
+                Some(format!("{} notes are outside the vanilla key range", import.out_of_range.len()))
+            };
+            Ok(Loaded { song: import.song, warning })
+        },
+        #[cfg(feature = "serde")]
+        Format::Json => Ok(Loaded {
+            song: crate::json::from_json(utf8(path, &buffer)?).map_err(|error| invalid(path, error))?,
+            warning: None,
+        }),
+        Format::Text => Ok(Loaded {
+            song: text::from_text(utf8(path, &buffer)?).map_err(|error| invalid(path, error))?,
+            warning: None,
+        }),
+        _ if options.recover => {
+            let (song, recovery) = parsers::recover_song(&buffer).map_err(|error| invalid(path, error))?;
+            Ok(Loaded { song, warning: recovery.map(|recovery| format!("recovered a damaged file: {}", recovery)) })
+        },
+        _ => Ok(Loaded {
+            song: parsers::song(&buffer).map_err(|error| invalid(path, error))?,
+            warning: None,
+        }),
+    }
+}
+
+/* headers with short strings fit in this, longer ones read the rest of the file */
+const HEADER_PREFIX: u64 = 4096;
+
+/// Reads just the header of a song, without the note blocks of `.nbs` files.
+///
+/// Other formats are loaded whole. Old `.nbs` files that don't store the song
+/// length get it from their note blocks.
+pub fn header(path: &Path) -> Result<Header, FileError> {
+    let format = Format::of(path).filter(|format| format.loadable()).ok_or_else(|| FileError::Format(path.to_owned()))?;
+    if format != Format::Nbs {
+        return load(path).map(|loaded| loaded.song.header);
+    }
+    let io_error = |error| FileError::Io(path.to_owned(), error);
+    let mut file = fs::File::open(path).map_err(io_error)?;
+    let mut buffer = Vec::new();
+    file.by_ref().take(HEADER_PREFIX).read_to_end(&mut buffer).map_err(io_error)?;
+    let header = match parsers::song_header(&buffer) {
+        Ok(header) => header,
+        Err(_) if buffer.len() as u64 == HEADER_PREFIX => {
+            file.read_to_end(&mut buffer).map_err(io_error)?;
+            parsers::song_header(&buffer).map_err(|error| invalid(path, error))?
+        },
+        Err(error) => return Err(invalid(path, error)),
+    };
+    if header.open_nbs_version > 0 && header.open_nbs_version < 3 {
+        return load(path).map(|loaded| loaded.song.header);
+    }
+    Ok(header)
+}
+
+/// Saves the song in the format of the path, `.wav` files are rendered with the default options.
+///
+/// Returns a warning when the file lacks something, like the instruments of a
+/// `.wav` whose samples couldn't be loaded.
+pub fn save(song: &Song, path: &Path) -> Result<Option<String>, FileError> {
+    save_with(song, path, &SaveOptions::default())
+}
+
+/// [`save`] with options.
+pub fn save_with(song: &Song, path: &Path, options: &SaveOptions) -> Result<Option<String>, FileError> {
+    let format = Format::of(path).ok_or_else(|| FileError::Format(path.to_owned()))?;
+    let bytes = match format {
+        Format::Nbs => crate::writers::song(song).map_err(|error| invalid(path, error))?,
+        Format::Text => text::to_text(song).into_bytes(),
+        #[cfg(feature = "serde")]
+        Format::Json => crate::json::to_json(song).into_bytes(),
+        Format::Midi => midi::export(song),
+        Format::Schematic => schematic::export(song).map_err(|error| invalid(path, error))?,
+        Format::Wav => return render::render_to_wav(song, path, &render::RenderOptions::default()).map_err(|error| invalid(path, error)),
+        Format::Datapack => {
+            let files = datapack::export(song, &options.datapack).map_err(|error| invalid(path, error))?;
+            return datapack::write(path, &files).map(|_| None).map_err(|error| invalid(path, error));
+        },
+    };
+    fs::write(path, bytes).map(|_| None).map_err(|error| FileError::Io(path.to_owned(), error))
+}
@@ -0,0 +1,54 @@
+//! JSON import and export of songs, with the `serde` feature.
+
+use std::fmt;
+
+use crate::parsers::{self, Expected, Song};
+
+#[derive(Debug)]
+pub enum JsonError {
+    Json(serde_json::Error),
+    /// Well formed JSON holding a song that couldn't be saved as a file.
+    Invalid(Expected),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::Json(error) => write!(f, "invalid json: {}", error),
+            JsonError::Invalid(expected) => write!(f, "invalid song: expected {}", expected),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+/// The song as pretty printed JSON.
+pub fn to_json(song: &Song) -> String {
+    serde_json::to_string_pretty(song).expect("songs always serialize")
+}
+
+/// Reads a song from JSON and checks it like a parsed file.
+pub fn from_json(input: &str) -> Result<Song, JsonError> {
+    let song: Song = serde_json::from_str(input).map_err(JsonError::Json)?;
+    parsers::validate(&song).map_err(JsonError::Invalid)?;
+    Ok(song)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::{tests::empty_song, Noteblock};
+
+    #[test]
+    fn negative_instruments_are_errors() {
+        let mut song = empty_song(1);
+        song.noteblocks.insert(0, 0, Noteblock { instrument: 0, key: 45, volume: 100, panning: 100, pitch: 0 });
+        assert_eq!(from_json(&to_json(&song)).unwrap(), song);
+
+        song.noteblocks.get_mut(0, 0).unwrap().instrument = -1;
+        match from_json(&to_json(&song)) {
+            Err(JsonError::Invalid(expected)) => assert_eq!(expected, Expected::Instrument { index: -1, instrument_count: 16 }),
+            other => panic!("expected an invalid instrument, got {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,22 @@
+//! Reading, writing and playing Note Block Studio songs.
+//!
+//! The terminal editor binary is built on top of this, other tools can use the
+//! same model, parser, writer and playback without the TUI.
+
+pub mod parsers;
+pub mod writers;
+pub mod notes;
+pub mod playback;
+pub mod render;
+pub mod midi;
+pub mod schematic;
+pub mod datapack;
+pub mod text;
+pub mod files;
+pub mod library;
+pub mod playlist;
+#[cfg(feature = "serde")]
+pub mod json;
+
+pub use notes::Notes;
+pub use parsers::{Header, Instrument, Layer, Noteblock, NoteblockSection, ParseError, Recovery, Song};
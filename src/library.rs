@@ -0,0 +1,444 @@
+//! An index of the songs in some folders, built from their headers only.
+//!
+//! The index is kept in a text file next to nothing else, one quoted line per
+//! folder and per song file, so a rescan only reads the headers of files whose
+//! size or modification time changed:
+//!
+//! ```text
+//! folder "/home/me/songs"
+//! song "/home/me/songs/Nyan Cat.nbs" 1690000000 2114 "Nyan Cat" "me" "" 1000 160 4
+//! error "/home/me/songs/broken.nbs" 1690000000 12 "invalid header at byte 8: ..."
+//! ```
+//!
+//! Searches are words that must all match, plain words look in the path, name and
+//! authors, `field:value` looks in one field. Numbers take `5`, `>5`, `<=5` or `5..10`.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
+
+use crate::files::{self, Format};
+use crate::parsers::Header;
+use crate::text::{self, TextError, Token};
+
+/// The header fields the library keeps.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub name: String,
+    pub author: String,
+    pub orig_author: String,
+    pub tempo: i16,
+    pub song_length: i16,
+    pub layer_count: i16,
+}
+
+impl From<&Header> for Metadata {
+    fn from(header: &Header) -> Self {
+        Metadata {
+            name: header.name.clone(),
+            author: header.author.clone(),
+            orig_author: header.orig_author.clone(),
+            tempo: header.tempo,
+            song_length: header.song_length,
+            layer_count: header.layer_count,
+        }
+    }
+}
+
+impl Metadata {
+    /// Length in seconds without tempo changers.
+    pub fn seconds(&self) -> f64 {
+        if self.tempo <= 0 {
+            return 0_f64;
+        }
+        self.song_length as f64 * 100_f64 / self.tempo as f64
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LibraryEntry {
+    pub path: PathBuf,
+    /// Seconds since the epoch, a rescan reads the file again when this or the size changes.
+    pub modified: u64,
+    pub size: u64,
+    /// Why the header couldn't be read, unreadable files stay indexed so rescans skip them too.
+    pub metadata: Result<Metadata, String>,
+}
+
+/// A column of the library to sort or search by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Path,
+    Name,
+    Author,
+    OrigAuthor,
+    Tempo,
+    SongLength,
+    LayerCount,
+}
+
+impl Field {
+    pub const ALL: [Field; 7] = [Field::Name, Field::Author, Field::OrigAuthor, Field::Tempo, Field::SongLength, Field::LayerCount, Field::Path];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Path => "path",
+            Field::Name => "name",
+            Field::Author => "author",
+            Field::OrigAuthor => "orig_author",
+            Field::Tempo => "tempo",
+            Field::SongLength => "song_length",
+            Field::LayerCount => "layer_count",
+        }
+    }
+
+    pub fn numeric(self) -> bool {
+        matches!(self, Field::Tempo | Field::SongLength | Field::LayerCount)
+    }
+
+    /* tempo is in ticks per second like the editor shows it, not the header's hundredths */
+    fn number(self, metadata: &Metadata) -> f64 {
+        match self {
+            Field::Tempo => metadata.tempo as f64 / 100_f64,
+            Field::SongLength => metadata.song_length as f64,
+            Field::LayerCount => metadata.layer_count as f64,
+            Field::Path | Field::Name | Field::Author | Field::OrigAuthor => 0_f64,
+        }
+    }
+
+    fn text(self, entry: &LibraryEntry) -> String {
+        match (self, &entry.metadata) {
+            (Field::Path, _) => entry.path.to_string_lossy().into_owned(),
+            (Field::Name, Ok(metadata)) => metadata.name.clone(),
+            (Field::Author, Ok(metadata)) => metadata.author.clone(),
+            (Field::OrigAuthor, Ok(metadata)) => metadata.orig_author.clone(),
+            _ => String::new(),
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Field::ALL.into_iter().find(|field| field.name() == name).ok_or_else(|| {
+            format!("unknown field {}, use {}", name, Field::ALL.map(Field::name).join(", "))
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Term {
+    Anywhere(String),
+    Text(Field, String),
+    Range(Field, Bound<f64>, Bound<f64>),
+}
+
+/// A parsed search, see the module docs for the syntax.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query {
+    terms: Vec<Term>,
+}
+
+fn query_number(field: Field, text: &str) -> Result<f64, String> {
+    text.trim().parse().map_err(|_| format!("{} needs a number, not {}", field, text))
+}
+
+impl FromStr for Query {
+    type Err = String;
+
+    fn from_str(search: &str) -> Result<Self, Self::Err> {
+        let mut terms = Vec::new();
+        for word in search.split_whitespace() {
+            let Some((name, value)) = word.split_once(':') else {
+                terms.push(Term::Anywhere(word.to_lowercase()));
+                continue;
+            };
+            let field: Field = name.parse()?;
+            if !field.numeric() {
+                terms.push(Term::Text(field, value.to_lowercase()));
+                continue;
+            }
+            let (low, high) = if let Some((low, high)) = value.split_once("..") {
+                (Bound::Included(query_number(field, low)?), Bound::Included(query_number(field, high)?))
+            } else if let Some(low) = value.strip_prefix(">=") {
+                (Bound::Included(query_number(field, low)?), Bound::Unbounded)
+            } else if let Some(high) = value.strip_prefix("<=") {
+                (Bound::Unbounded, Bound::Included(query_number(field, high)?))
+            } else if let Some(low) = value.strip_prefix('>') {
+                (Bound::Excluded(query_number(field, low)?), Bound::Unbounded)
+            } else if let Some(high) = value.strip_prefix('<') {
+                (Bound::Unbounded, Bound::Excluded(query_number(field, high)?))
+            } else {
+                let number = query_number(field, value)?;
+                (Bound::Included(number), Bound::Included(number))
+            };
+            terms.push(Term::Range(field, low, high));
+        }
+        Ok(Query { terms })
+    }
+}
+
+impl Query {
+    pub fn matches(&self, entry: &LibraryEntry) -> bool {
+        self.terms.iter().all(|term| match term {
+            Term::Anywhere(word) => [Field::Path, Field::Name, Field::Author, Field::OrigAuthor]
+                .into_iter()
+                .any(|field| field.text(entry).to_lowercase().contains(word.as_str())),
+            Term::Text(field, word) => field.text(entry).to_lowercase().contains(word.as_str()),
+            Term::Range(field, low, high) => entry.metadata.as_ref()
+                .is_ok_and(|metadata| (*low, *high).contains(&field.number(metadata))),
+        })
+    }
+}
+
+/// What a rescan did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScanReport {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+    /// Folders that couldn't be read, their songs are kept until they can.
+    pub errors: Vec<String>,
+}
+
+impl fmt::Display for ScanReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} added, {} updated, {} removed, {} unchanged", self.added, self.updated, self.removed, self.unchanged)?;
+        for error in &self.errors {
+            write!(f, "; {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum LibraryError {
+    Io(PathBuf, std::io::Error),
+    Index(PathBuf, TextError),
+}
+
+impl fmt::Display for LibraryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LibraryError::Io(path, error) => write!(f, "{}: {}", path.display(), error),
+            LibraryError::Index(path, error) => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl std::error::Error for LibraryError {}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Library {
+    pub folders: Vec<PathBuf>,
+    /// Sorted by path.
+    pub entries: Vec<LibraryEntry>,
+}
+
+/// Where the index is kept when no other file is given, in the user's cache folder.
+pub fn default_index() -> PathBuf {
+    let cache = std::env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")));
+    match cache {
+        Some(cache) => cache.join("nbs_tui").join("library.txt"),
+        None => PathBuf::from(".nbs_tui_library.txt"),
+    }
+}
+
+/* loadable songs under the folder, hidden files and folders left out */
+fn walk(folder: &Path, songs: &mut Vec<(PathBuf, fs::Metadata)>) -> std::io::Result<()> {
+    for entry in fs::read_dir(folder)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        let Ok(metadata) = fs::metadata(&path) else {
+            continue; //broken links
+        };
+        if metadata.is_dir() {
+            //a subfolder that can't be read shouldn't stop the rest of the scan
+            let _ = walk(&path, songs);
+        } else if Format::of(&path).is_some_and(|format| format.loadable()) {
+            songs.push((path, metadata));
+        }
+    }
+    Ok(())
+}
+
+fn number_at<T: FromStr>(tokens: &[Token], index: usize, name: &str) -> Result<T, String> {
+    tokens.get(index).ok_or_else(|| format!("missing the {}", name)).and_then(|token| text::number(token, name))
+}
+
+fn text_at(tokens: &[Token], index: usize, name: &str) -> Result<String, String> {
+    tokens.get(index).ok_or_else(|| format!("missing the {}", name)).and_then(|token| text::text(token, name))
+}
+
+fn modified_seconds(metadata: &fs::Metadata) -> u64 {
+    metadata.modified().ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_secs())
+}
+
+impl Library {
+    /// Reads the index, a missing file is an empty library.
+    pub fn load(index: &Path) -> Result<Library, LibraryError> {
+        let input = match fs::read_to_string(index) {
+            Ok(input) => input,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Library::default()),
+            Err(error) => return Err(LibraryError::Io(index.to_owned(), error)),
+        };
+        let mut library = Library::default();
+        for (number, line) in input.lines().enumerate() {
+            let error = |message: String| LibraryError::Index(index.to_owned(), TextError { line: number + 1, message });
+            let tokens = text::tokens(line).map_err(error)?;
+            match tokens.first() {
+                None => {},
+                Some(Token::Word(word)) if word.starts_with('#') => {},
+                Some(Token::Word(word)) if word == "folder" => library.folders.push(PathBuf::from(text_at(&tokens, 1, "folder").map_err(error)?)),
+                Some(Token::Word(word)) if word == "song" || word == "error" => {
+                    let entry = (|| {
+                        let metadata = if word == "song" {
+                            Ok(Metadata {
+                                name: text_at(&tokens, 4, "name")?,
+                                author: text_at(&tokens, 5, "author")?,
+                                orig_author: text_at(&tokens, 6, "original author")?,
+                                tempo: number_at(&tokens, 7, "tempo")?,
+                                song_length: number_at(&tokens, 8, "song length")?,
+                                layer_count: number_at(&tokens, 9, "layer count")?,
+                            })
+                        } else {
+                            Err(text_at(&tokens, 4, "error")?)
+                        };
+                        Ok::<_, String>(LibraryEntry {
+                            path: PathBuf::from(text_at(&tokens, 1, "path")?),
+                            modified: number_at(&tokens, 2, "modification time")?,
+                            size: number_at(&tokens, 3, "size")?,
+                            metadata,
+                        })
+                    })().map_err(error)?;
+                    library.entries.push(entry);
+                },
+                Some(_) => return Err(error("expected folder, song or error".to_owned())),
+            }
+        }
+        library.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(library)
+    }
+
+    pub fn save(&self, index: &Path) -> Result<(), LibraryError> {
+        let mut output = String::from("# nbs_tui library index\n");
+        for folder in &self.folders {
+            output.push_str(&format!("folder {}\n", text::quote(&folder.to_string_lossy())));
+        }
+        for entry in &self.entries {
+            let path = text::quote(&entry.path.to_string_lossy());
+            match &entry.metadata {
+                Ok(metadata) => output.push_str(&format!(
+                    "song {} {} {} {} {} {} {} {} {}\n",
+                    path, entry.modified, entry.size,
+                    text::quote(&metadata.name), text::quote(&metadata.author), text::quote(&metadata.orig_author),
+                    metadata.tempo, metadata.song_length, metadata.layer_count,
+                )),
+                Err(error) => output.push_str(&format!("error {} {} {} {}\n", path, entry.modified, entry.size, text::quote(error))),
+            }
+        }
+        if let Some(parent) = index.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(|error| LibraryError::Io(parent.to_owned(), error))?;
+        }
+        fs::write(index, output).map_err(|error| LibraryError::Io(index.to_owned(), error))
+    }
+
+    /// Adds a folder if it isn't in the library yet, it's indexed on the next [`Library::scan`].
+    pub fn add_folder(&mut self, folder: &Path) -> bool {
+        let folder = folder.canonicalize().unwrap_or_else(|_| folder.to_owned());
+        if self.folders.contains(&folder) {
+            return false;
+        }
+        self.folders.push(folder);
+        true
+    }
+
+    /// Removes a folder and the songs found through it.
+    pub fn remove_folder(&mut self, folder: &Path) -> bool {
+        let folder = folder.canonicalize().unwrap_or_else(|_| folder.to_owned());
+        let count = self.folders.len();
+        self.folders.retain(|kept| *kept != folder);
+        let folders = &self.folders;
+        self.entries.retain(|entry| folders.iter().any(|kept| entry.path.starts_with(kept)));
+        count != self.folders.len()
+    }
+
+    /// Walks the folders again, reading headers only for files that are new or changed.
+    pub fn scan(&mut self) -> ScanReport {
+        let mut report = ScanReport::default();
+        let mut old: HashMap<PathBuf, LibraryEntry> = self.entries.drain(..).map(|entry| (entry.path.clone(), entry)).collect();
+        let mut songs = Vec::new();
+        for folder in &self.folders {
+            if let Err(error) = walk(folder, &mut songs) {
+                report.errors.push(format!("couldn't scan {}: {}", folder.display(), error));
+                //keep what was there, the folder might be an unplugged drive
+                let kept: Vec<PathBuf> = old.keys().filter(|path| path.starts_with(folder)).cloned().collect();
+                for path in kept {
+                    self.entries.extend(old.remove(&path));
+                }
+            }
+        }
+        songs.sort_by(|a, b| a.0.cmp(&b.0));
+        songs.dedup_by(|a, b| a.0 == b.0); //folders inside other folders
+        for (path, file) in songs {
+            let modified = modified_seconds(&file);
+            let size = file.len();
+            match old.remove(&path) {
+                Some(entry) if entry.modified == modified && entry.size == size => {
+                    report.unchanged += 1;
+                    self.entries.push(entry);
+                    continue;
+                },
+                Some(_) => report.updated += 1,
+                None => report.added += 1,
+            }
+            let metadata = files::header(&path).map(|header| Metadata::from(&header)).map_err(|error| match error {
+                files::FileError::Invalid(_, error) => error.to_string(),
+                error => error.to_string(),
+            });
+            self.entries.push(LibraryEntry { path, modified, size, metadata });
+        }
+        report.removed = old.len();
+        self.entries.sort_by(|a, b| a.path.cmp(&b.path));
+        report
+    }
+
+    /// Indices of the entries the query matches, sorted by a field. Unreadable files go last.
+    pub fn find(&self, query: &Query, sort: Field, descending: bool) -> Vec<usize> {
+        let mut found: Vec<usize> = (0..self.entries.len()).filter(|&index| query.matches(&self.entries[index])).collect();
+        found.sort_by(|&a, &b| {
+            let (a, b) = (&self.entries[a], &self.entries[b]);
+            let order = match (&a.metadata, &b.metadata) {
+                (Ok(_), Err(_)) => return Ordering::Less,
+                (Err(_), Ok(_)) => return Ordering::Greater,
+                (Ok(a_metadata), Ok(b_metadata)) if sort.numeric() => {
+                    sort.number(a_metadata).total_cmp(&sort.number(b_metadata))
+                },
+                _ => sort.text(a).to_lowercase().cmp(&sort.text(b).to_lowercase()),
+            };
+            let order = order.then_with(|| a.path.cmp(&b.path));
+            if descending { order.reverse() } else { order }
+        });
+        found
+    }
+}
@@ -0,0 +1,179 @@
+use std::path::PathBuf;
+
+use nbs_tui::library::{self, Field, Library, Query};
+use ratatui::{
+    buffer::Buffer,
+    layout::{Constraint, Rect},
+    style::{Color, Modifier, Style},
+    widgets::{Block, Borders, Cell, Clear, Paragraph, Row, StatefulWidget, Table, TableState, Widget},
+};
+
+/// The songs of the library index, searched and sorted.
+#[derive(Debug)]
+pub struct LibraryView {
+    pub library: Library,
+    pub index: PathBuf,
+    /// Indices into the library entries, in the order shown.
+    pub rows: Vec<usize>,
+    pub table_state: TableState,
+    pub search: String,
+    /// Typing goes into the search while this is set.
+    pub searching: bool,
+    pub search_error: Option<String>,
+    pub sort: Field,
+    pub descending: bool,
+    pub status: Option<String>,
+}
+
+impl LibraryView {
+    /// Loads the default index and rescans its folders.
+    pub fn open() -> LibraryView {
+        let index = library::default_index();
+        let (library, status) = match Library::load(&index) {
+            Ok(library) => (library, None),
+            Err(error) => (Library::default(), Some(format!("Couldn't load the library: {}", error))),
+        };
+        let mut view = LibraryView {
+            library,
+            index,
+            rows: Vec::new(),
+            table_state: TableState::default(),
+            search: String::new(),
+            searching: false,
+            search_error: None,
+            sort: Field::Name,
+            descending: false,
+            status,
+        };
+        if view.status.is_none() {
+            view.rescan();
+        }
+        view
+    }
+
+    pub fn rescan(&mut self) {
+        if self.library.folders.is_empty() {
+            self.status = Some("No folders yet, add some with `nbs_tui library add <folder>`".to_owned());
+            return;
+        }
+        let report = self.library.scan();
+        self.status = Some(match self.library.save(&self.index) {
+            Ok(()) => format!("Scanned: {}", report),
+            Err(error) => format!("Couldn't save the library: {}", error),
+        });
+        self.refresh();
+    }
+
+    /// Applies the search and sort again, keeping the selected song selected if it's still shown.
+    pub fn refresh(&mut self) {
+        let selected = self.selected().map(|entry| entry.path.clone());
+        let query: Query = match self.search.parse() {
+            Ok(query) => query,
+            Err(error) => {
+                self.search_error = Some(error);
+                return;
+            },
+        };
+        self.search_error = None;
+        self.rows = self.library.find(&query, self.sort, self.descending);
+        let entries = &self.library.entries;
+        let index = selected.and_then(|path| self.rows.iter().position(|&row| entries[row].path == path));
+        self.select(index.unwrap_or(0));
+    }
+
+    pub fn selected(&self) -> Option<&library::LibraryEntry> {
+        let row = *self.rows.get(self.table_state.selected()?)?;
+        self.library.entries.get(row)
+    }
+
+    pub fn select(&mut self, index: usize) {
+        if self.rows.is_empty() {
+            self.table_state.select(None);
+        } else {
+            self.table_state.select(Some(index.min(self.rows.len() - 1)));
+        }
+    }
+
+    pub fn move_selection(&mut self, offset: isize) {
+        let selected = self.table_state.selected().unwrap_or(0);
+        self.select(selected.saturating_add_signed(offset));
+    }
+
+    pub fn next_sort(&mut self) {
+        let index = Field::ALL.iter().position(|&field| field == self.sort).unwrap_or(0);
+        self.sort = Field::ALL[(index + 1) % Field::ALL.len()];
+        self.refresh();
+    }
+}
+
+/// Draws the library, `queued` is how many songs wait in the play queue.
+#[derive(Debug)]
+pub struct LibraryWidget {
+    pub queued: usize,
+}
+
+impl StatefulWidget for LibraryWidget {
+    type State = LibraryView;
+
+    fn render(self, area: Rect, buf: &mut Buffer, view: &mut LibraryView) {
+        if area.area() == 0 {
+            return;
+        }
+        Clear.render(area, buf);
+        let block = Block::default().borders(Borders::ALL).title(format!(
+            " Library: {} of {} songs, by {} {}, {} queued ",
+            view.rows.len(),
+            view.library.entries.len(),
+            view.sort,
+            if view.descending { "descending" } else { "ascending" },
+            self.queued,
+        ));
+        let inner = block.inner(area);
+        block.render(area, buf);
+        if inner.height < 2 {
+            return;
+        }
+        let table_area = Rect::new(inner.x, inner.y, inner.width, inner.height - 1);
+        let line = Rect::new(inner.x, inner.bottom() - 1, inner.width, 1);
+
+        let rows: Vec<Row> = view.rows.iter().map(|&row| {
+            let entry = &view.library.entries[row];
+            let file_name = entry.path.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned());
+            match &entry.metadata {
+                Ok(metadata) => Row::new(vec![
+                    Cell::from(if metadata.name.is_empty() { file_name } else { metadata.name.clone() }),
+                    Cell::from(metadata.author.clone()),
+                    Cell::from(metadata.orig_author.clone()),
+                    Cell::from(format!("{:.2}", metadata.tempo as f64 / 100_f64)),
+                    Cell::from(format!("{} {}", metadata.song_length, crate::minutes(metadata.seconds()))),
+                    Cell::from(metadata.layer_count.to_string()),
+                ]),
+                Err(error) => Row::new(vec![Cell::from(file_name), Cell::from(error.clone())]).style(Style::default().fg(Color::Red)),
+            }
+        }).collect();
+        let widths = [
+            Constraint::Percentage(34),
+            Constraint::Percentage(20),
+            Constraint::Percentage(20),
+            Constraint::Length(6),
+            Constraint::Length(11),
+            Constraint::Length(6),
+        ];
+        let table = Table::new(rows)
+            .header(Row::new(vec!["name", "author", "orig_author", "tempo", "song_length", "layers"]).style(Style::default().add_modifier(Modifier::BOLD)))
+            .widths(&widths)
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        StatefulWidget::render(table, table_area, buf, &mut view.table_state);
+
+        let (text, style) = if let Some(error) = &view.search_error {
+            (format!("/{}  {}", view.search, error), Style::default().fg(Color::Red))
+        } else if view.searching || !view.search.is_empty() {
+            (format!("/{}", view.search), Style::default())
+        } else if let Some(status) = &view.status {
+            (status.clone(), Style::default().fg(Color::DarkGray))
+        } else {
+            ("Enter plays, a queues, / searches, s sorts, d flips the order, r rescans, c clears the queue".to_owned(), Style::default().fg(Color::DarkGray))
+        };
+        Paragraph::new(text).style(style).render(line, buf);
+    }
+}
@@ -1,222 +1,313 @@
-use std::io::{self, Read};
-use std::thread;
-use std::time::Instant;
-use std::{fs::File, time::Duration};
+mod editor;
+mod file_browser;
+mod library_view;
+mod noteblock_widget;
 
-use editor::{AppResult};
-use parsers::{Song, song};
-use rodio::{OutputStream, Decoder, source::Buffered, Source};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand};
+use nbs_tui::files::{self, Loaded, LoadOptions, SaveOptions};
+use nbs_tui::library::{self, Field, Library, Query};
+use nbs_tui::midi;
+use nbs_tui::playback::{self, Player, Schedule};
+use nbs_tui::render::{self, RenderOptions, SampleFormat, StemGrouping};
+
+/// Edit, play and convert Note Block Studio songs.
+#[derive(Parser)]
+#[command(name = "nbs_tui", version)]
+struct Cli {
+    /// Load damaged .nbs files as far as they go instead of failing, with a warning about what was lost
+    #[arg(long, global = true)]
+    recover: bool,
+    /// Ticks per second MIDI files are imported at, their notes are rounded to these ticks
+    #[arg(long, global = true, default_value_t = midi::ImportOptions::default().tempo)]
+    midi_tempo: f64,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-use crate::parsers::{Instrument, Layer, NoteblockSection};
+#[derive(Subcommand)]
+enum Command {
+    /// Open a song in the editor (the default without a command)
+    Edit { file: Option<PathBuf> },
+    /// Play a song without the editor
+    Play { file: PathBuf },
+    /// Print what's in a song
+    Info { file: PathBuf },
+    /// Convert a song, formats are picked by extension (.nbs, .nbst, .json, .mid, .schem, .wav) and a folder makes a datapack
+    Convert {
+        input: PathBuf,
+        output: PathBuf,
+        /// Sound event a datapack plays for a custom instrument, like `--sound Piano=custom:piano`
+        #[arg(long = "sound", value_name = "NAME=EVENT", value_parser = sound_event)]
+        sounds: Vec<(String, String)>,
+    },
+    /// Render a song to a wav file
+    Render {
+        file: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Write 32-bit float samples instead of 16-bit ones
+        #[arg(long)]
+        float: bool,
+        #[arg(long, default_value_t = 44100)]
+        sample_rate: u32,
+        /// How many times a looping song loops
+        #[arg(long, default_value_t = 0)]
+        loops: u32,
+        /// Seconds after the end of the song that ringing notes fade out over
+        #[arg(long, default_value_t = RenderOptions::default().fade_out)]
+        fade_out: f64,
+        /// Folder with the instrument samples
+        #[arg(long, default_value = "sounds")]
+        sounds: PathBuf,
+        /// Also write every layer (or instrument) that makes a sound to its own wav file in this folder
+        #[arg(long)]
+        stems: Option<PathBuf>,
+        /// What each stem holds, layer or instrument
+        #[arg(long, default_value = "layer", requires = "stems")]
+        by: StemGrouping,
+        /// Stem file names, {song} is the song name, {index} the layer or instrument number and {name} its name
+        #[arg(long, default_value = render::DEFAULT_STEM_PATTERN, requires = "stems")]
+        pattern: String,
+    },
+    /// Keep an index of the songs in some folders
+    Library {
+        /// Index file, by default in the user's cache folder
+        #[arg(long)]
+        index: Option<PathBuf>,
+        #[command(subcommand)]
+        command: LibraryCommand,
+    },
+}
 
-mod parsers;
-mod editor;
-mod noteblock_widget;
+#[derive(Subcommand)]
+enum LibraryCommand {
+    /// Add folders to the library and scan them
+    Add {
+        #[arg(required = true)]
+        folders: Vec<PathBuf>,
+    },
+    /// Remove folders and their songs from the library
+    Remove {
+        #[arg(required = true)]
+        folders: Vec<PathBuf>,
+    },
+    /// Scan the folders again, only new and changed files are read
+    Scan,
+    /// List the songs, a search like `author:someone tempo:>10 cat` narrows them down
+    List {
+        /// name, author, orig_author, tempo, song_length, layer_count or path
+        #[arg(long, default_value = "name")]
+        sort: Field,
+        #[arg(long)]
+        descending: bool,
+        search: Vec<String>,
+    },
+}
 
-const DEFAULT_INSTRUMENTS: [&str; 16] = ["harp","dbass","bdrum","sdrum","click","guitar","flute","bell","icechime","xylobone","iron_xylophone","cow_bell","didgeridoo","bit","banjo","pling"];
-
-fn play_song(song : &Song){
-
-    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-    let mut last_tick: i32 = -1;
-    let mut sounds: Vec<Buffered<Decoder<File>>> = Vec::new();
-    let mut total_instruments: Vec<&Instrument> = Vec::new();
-    let mut effective_layers: Vec<&Layer> = Vec::new();
-    let mut default_instruments: Vec<Instrument> = Vec::new();
-    for name in DEFAULT_INSTRUMENTS {
-        // println!("Instrument {:?}",name);
-        sounds.push(Decoder::new(std::fs::File::open(format!("sounds/{}.ogg",name)).unwrap()).unwrap().buffered());
-        default_instruments.push(Instrument {
-            name: name.into(),
-            sound_file: name.into(),
-            sound_key: 45,
-            press_key: 1
-        });
-    };
-    for i in 0..default_instruments.len() { //so this is how you rust
-        total_instruments.push(&default_instruments[i]);
-    };
+type CliResult = Result<(), Box<dyn std::error::Error>>;
 
-    let mut tempo_changer_index: i8 = -1;
-    for instrument in &song.custom_instruments {
-        // println!("Custom instrument {:?} at sounds/{:?}",instrument.name,instrument.sound_file);
-        if instrument.name == "Tempo Changer" {
-            tempo_changer_index = sounds.len() as i8;
-            sounds.push(Decoder::new(std::fs::File::open("sounds/harp.ogg").unwrap()).unwrap().buffered()); //dummy sound because i dont wanna option
-            total_instruments.push(instrument);
-            continue;
-        }
-        sounds.push(Decoder::new(std::fs::File::open(format!("sounds/{}",instrument.sound_file)).unwrap()).unwrap().buffered());
-        total_instruments.push(instrument);
+fn sound_event(mapping: &str) -> Result<(String, String), String> {
+    match mapping.split_once('=') {
+        Some((name, event)) if !name.is_empty() && !event.is_empty() => Ok((name.to_owned(), event.to_owned())),
+        _ => Err(format!("{} isn't NAME=EVENT", mapping)),
     }
+}
 
-    let defaultLayer: Layer = Layer {
-        name: "default_layer".to_owned(),
-        locked: 0,
-        volume: 100,
-        stereo: 100
-    };
-    if !song.layers.is_empty() {
-        for layer in &song.layers{
-            effective_layers.push(layer);
-        }
-    } else {
-        for _ in 0..song.header.layer_count {
-            effective_layers.push(&defaultLayer);
-        }
+/* loads the song and mentions anything that went missing on the way */
+fn load(file: &Path, options: &LoadOptions) -> Result<Loaded, files::FileError> {
+    let loaded = files::load_with(file, options)?;
+    if let Some(warning) = &loaded.warning {
+        eprintln!("warning: {}: {}", file.display(), warning);
     }
-    
-
-
-
-    let mut loop_count = 0;
-    // println!("tempo is {:?}tps",(song.header.tempo as f64 / 100_f64));
-
-
-    
-    let mut tick_length : f64;
-    let mut tick_duration : Duration;
-    let mut tick: i32=-1;
-    let unaccuracy: Duration;
-    if std::env::consts::OS == "windows" {
-        unaccuracy = Duration::new(u64::MAX, 0); // never trust
-    }else{
-        unaccuracy = Duration::new(0, 5*1000*1000); // 5 ms untrustworthyness
-    }
-    loop {
-        tick_length = (100000000_f64/(song.header.tempo as f64)) as f64;
-        tick_duration = std::time::Duration::from_micros(tick_length as u64);
-
-
-        let mut layer_pos: i32=-1;
-        let mut iterator = song.noteblocks.iter();
-        let mut mixer: (std::sync::Arc<rodio::dynamic_mixer::DynamicMixerController<f32>>, rodio::dynamic_mixer::DynamicMixer<f32>) = rodio::dynamic_mixer::mixer(2,44100);
-        
-        if iterator.find(|section| {
-            match section{
-                NoteblockSection::SetTick(check) => {
-                    if check>=&tick {
-                        last_tick = tick;
-                        tick = *check;
-                        return true;
-                    }else{
-                        return false;
-                    }
-                },
-                NoteblockSection::SetLayer(_) => false,
-                NoteblockSection::Noteblock(_) => false,
-            }
-        }).is_none() {
-            // println!("Couldn't find starting position for {}",tick);
-            break;
-        } else {
-            // println!("beginning tick {:?} at time {:?}",tick,std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis());
-            // std::thread::sleep(tick_duration.mul_f64((tick-last_tick) as f64));
-        }
+    Ok(loaded)
+}
 
-        let mut lastTime = Instant::now();
-        let mut drift: u128 = 0;
-        
-        for section in iterator {
-            // println!("doing section {:?}",section);
-            match section{
-                parsers::NoteblockSection::SetTick(num) => {
-
-                    let duration = tick_duration.mul_f64((tick-last_tick) as f64);
-                    if duration > unaccuracy {
-                        std::thread::sleep(duration.saturating_sub(unaccuracy))
-                    }
-                    while lastTime.elapsed()<duration { std::hint::spin_loop(); } //accurate waiting
-
-                    drift+=lastTime.elapsed().as_nanos()-duration.as_nanos();
-                    last_tick=tick;
-                    tick=i32::from(*num);
-                    lastTime = Instant::now();
-
-                    stream_handle.play_raw(mixer.1.convert_samples()).unwrap();
-                    mixer = rodio::dynamic_mixer::mixer(2,44100);
-                }
-                parsers::NoteblockSection::SetLayer(num) => layer_pos=i32::from(*num),
-                parsers::NoteblockSection::Noteblock(noteblock) => {
-                    if noteblock.instrument == tempo_changer_index {
-                        tick_length = (1000000_f64/(noteblock.pitch as f64 / 15_f64)) as f64;
-                        tick_duration = std::time::Duration::from_micros(tick_length as u64);
-                        // println!("Set tempo to {}",(noteblock.pitch as f64 / 15_f64));
-                        continue;
-                    }
-                    mixer.0.add(
-                        sounds[noteblock.instrument as usize].clone()
-                        .speed(
-                            (2_f64.powf(
-                                (
-                                    (
-                                        (noteblock.key as f64)-(total_instruments[noteblock.instrument as usize].sound_key as f64)
-                                    )+(
-                                        (noteblock.pitch as f64)/100_f64
-                                    )
-                                )*(1_f64/12_f64))) as f32
-                        )
-                        
-                        .amplify((noteblock.volume as f32 * effective_layers[layer_pos as usize].volume as f32)/10000_f32)
-                        .convert_samples()
-                    );
-                    // println!("noteblock at {:?},{:?}: {:?}", tick,layer_pos, noteblock);
+fn minutes(seconds: f64) -> String {
+    let seconds = seconds.max(0_f64) as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
 
-                }
-            }
-        }
-        //play last tick
-        let duration = tick_duration.mul_f64((tick-last_tick) as f64);
-        if duration > unaccuracy {
-            std::thread::sleep(duration.saturating_sub(unaccuracy))
+fn play(file: &Path, options: &LoadOptions) -> CliResult {
+    let song = load(file, options)?.song;
+    let player = Player::new();
+    if let Some(error) = &player.output_error {
+        eprintln!("warning: playing silently, no sound output: {}", error);
+    }
+    let total = match playback::header_loops(&song) {
+        Some(_) => {
+            let mut schedule = Schedule::new(&song);
+            schedule.by_ref().for_each(drop);
+            minutes(schedule.end_time())
+        },
+        None => "looping".to_owned(),
+    };
+    let name = if song.header.name.is_empty() { file.display().to_string() } else { song.header.name.clone() };
+    let track = player.load(song);
+    if let Some(warning) = &track.warning {
+        eprintln!("warning: {}", warning);
+    }
+    player.play(0, track);
+    let mut stderr = std::io::stderr();
+    while !player.silent() {
+        let status = player.status();
+        if let Some(tick) = status.tick {
+            let _ = write!(stderr, "\r{}  {} / {}  tick {}   ", name, minutes(status.seconds), total, tick);
+            let _ = stderr.flush();
         }
-        while lastTime.elapsed()<duration { std::hint::spin_loop(); } //accurate waiting
-
-        drift+=lastTime.elapsed().as_nanos()-duration.as_nanos();
-        lastTime = Instant::now();
-
-        stream_handle.play_raw(mixer.1.convert_samples()).unwrap();
-
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    eprintln!();
+    Ok(())
+}
 
-        //wait til beat
-        let duration = tick_duration.mul_f64((tick%4) as f64); //loops at the beat
-        if duration > unaccuracy {
-            std::thread::sleep(duration.saturating_sub(unaccuracy))
+fn info(file: &Path, options: &LoadOptions) -> CliResult {
+    let song = load(file, options)?.song;
+    let header = &song.header;
+    println!("file: {}", file.display());
+    for (label, value) in [("name", &header.name), ("author", &header.author), ("original author", &header.orig_author), ("description", &header.description), ("imported from", &header.original_file_name)] {
+        if !value.is_empty() {
+            println!("{}: {}", label, value);
         }
-        while lastTime.elapsed()<duration { std::hint::spin_loop(); } //accurate waiting
-        
-
-        // println!("Drift was {:?}ms",(drift as f64)/1000000_f64);
-        if song.header.looping==0 {break;}
-        tick=song.header.loop_start_tick as i32;
-        loop_count+=1;
-        if loop_count == song.header.loop_count { break; }
-
-        // println!("====================================================LOOPED====================================================");
+    }
+    if header.open_nbs_version == 0 {
+        println!("format: classic Note Block Studio");
+    } else {
+        println!("format: Open Note Block Studio version {}", header.open_nbs_version);
+    }
+    let mut schedule = Schedule::with_loops(&song, Some(0));
+    schedule.by_ref().for_each(drop);
+    let last_tick = song.noteblocks.last_tick().unwrap_or(0);
+    println!("tempo: {:.2} ticks per second", header.tempo as f64 / 100_f64);
+    println!("length: {} ticks, {}", last_tick + 1, minutes(schedule.end_time()));
+    println!("time signature: {}/4", header.time_signature);
+    println!("notes: {} on {} layers", song.noteblocks.len(), header.layer_count);
+    match playback::header_loops(&song) {
+        Some(0) => println!("loop: off"),
+        Some(loops) => println!("loop: from tick {}, {} times", header.loop_start_tick, loops),
+        None => println!("loop: from tick {}, forever", header.loop_start_tick),
     }
 
-    std::thread::sleep(std::time::Duration::from_millis(1000));
+    let instruments = playback::instruments(&song);
+    let mut used: BTreeMap<usize, usize> = BTreeMap::new();
+    for (_, _, noteblock) in song.noteblocks.iter() {
+        *used.entry(noteblock.instrument as usize).or_default() += 1;
+    }
+    println!("instruments:");
+    for (index, count) in used {
+        let name = instruments.get(index).map_or("missing", |instrument| instrument.name.as_str());
+        let custom = if index >= header.vanilla_instrument_count.max(0) as usize { " (custom)" } else { "" };
+        println!("  {}{}: {} notes", name, custom, count);
+    }
+    Ok(())
 }
 
+fn convert(input: &Path, output: &Path, options: &LoadOptions, sounds: Vec<(String, String)>) -> CliResult {
+    let song = load(input, options)?.song;
+    let mut save_options = SaveOptions::default();
+    save_options.datapack.sounds.extend(sounds);
+    if let Some(warning) = files::save_with(&song, output, &save_options)? {
+        eprintln!("warning: {}: {}", output.display(), warning);
+    }
+    Ok(())
+}
 
-fn main() {
-    // let handle = thread::spawn(|| {
-        editor::start().unwrap();
-        let test: Vec<String> = Vec::new();
-    // });
-    // let args: Vec<String> = std::env::args().collect();
-	// let mut f = File::open("songs/8xthing.nbs").unwrap();
-    // if args.len()>1 {
-    //     f = File::open(format!("songs/{}",args[1])).unwrap();
-    // }
-    // let mut buffer = vec!();
-    // f.read_to_end(&mut buffer).unwrap();
-    // let (_, song) = song(&buffer).unwrap();
-    // play_song(song);
-    
-
+/* stems go next to the full mix, when asked for */
+fn render(file: &Path, load_options: &LoadOptions, output: &Path, options: RenderOptions, stems: Option<(PathBuf, StemGrouping, String)>) -> CliResult {
+    let song = load(file, load_options)?.song;
+    let warning = render::render_to_wav(&song, output, &options)?;
+    if let Some(warning) = &warning {
+        eprintln!("warning: {}", warning);
+    }
+    if let Some((folder, grouping, pattern)) = stems {
+        std::fs::create_dir_all(&folder).map_err(|error| format!("couldn't create {}: {}", folder.display(), error))?;
+        let stems = render::render_stems(&song, &folder, &pattern, grouping, &options)?;
+        //the same samples are usually missing for both
+        if let Some(stem_warning) = stems.warning.as_ref().filter(|stem_warning| warning.as_ref() != Some(*stem_warning)) {
+            eprintln!("warning: stems: {}", stem_warning);
+        }
+        for path in stems.files {
+            println!("{}", path.display());
+        }
+    }
+    Ok(())
+}
 
+fn library(index: Option<PathBuf>, command: LibraryCommand) -> CliResult {
+    let index = index.unwrap_or_else(library::default_index);
+    let mut library = Library::load(&index)?;
+    let scan = |library: &mut Library| -> CliResult {
+        let report = library.scan();
+        library.save(&index)?;
+        println!("{}: {}", index.display(), report);
+        Ok(())
+    };
+    match command {
+        LibraryCommand::Add { folders } => {
+            for folder in folders {
+                if !folder.is_dir() {
+                    return Err(format!("{} isn't a folder", folder.display()).into());
+                }
+                library.add_folder(&folder);
+            }
+            scan(&mut library)
+        },
+        LibraryCommand::Remove { folders } => {
+            for folder in folders {
+                if !library.remove_folder(&folder) {
+                    eprintln!("warning: {} isn't in the library", folder.display());
+                }
+            }
+            library.save(&index)?;
+            Ok(())
+        },
+        LibraryCommand::Scan => scan(&mut library),
+        LibraryCommand::List { sort, descending, search } => {
+            let query: Query = search.join(" ").parse()?;
+            for index in library.find(&query, sort, descending) {
+                let entry = &library.entries[index];
+                match &entry.metadata {
+                    Ok(metadata) => println!("{}\t{}\t{}\t{:.2}\t{}\t{}\t{}", entry.path.display(), metadata.name, metadata.author, metadata.tempo as f64 / 100_f64, metadata.song_length, minutes(metadata.seconds()), metadata.layer_count),
+                    Err(error) => println!("{}\terror: {}", entry.path.display(), error),
+                }
+            }
+            Ok(())
+        },
+    }
+}
 
-    
+fn run(command: Command, options: LoadOptions) -> CliResult {
+    match command {
+        Command::Edit { file } => editor::start(file, options),
+        Command::Play { file } => play(&file, &options),
+        Command::Info { file } => info(&file, &options),
+        Command::Convert { input, output, sounds } => convert(&input, &output, &options, sounds),
+        Command::Render { file, output, float, sample_rate, loops, fade_out, sounds, stems, by, pattern } => render(&file, &options, &output, RenderOptions {
+            sample_rate,
+            format: if float { SampleFormat::Float32 } else { SampleFormat::Int16 },
+            loops,
+            fade_out,
+            sounds,
+        }, stems.map(|folder| (folder, by, pattern))),
+        Command::Library { index, command } => library(index, command),
+    }
+}
 
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let options = LoadOptions {
+        recover: cli.recover,
+        midi: midi::ImportOptions { tempo: cli.midi_tempo },
+    };
+    match run(cli.command.unwrap_or(Command::Edit { file: None }), options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        },
+    }
 }
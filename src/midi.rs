@@ -0,0 +1,788 @@
+//! Standard MIDI File import and export.
+
+use std::fmt;
+
+use nom::bytes::complete::{tag, take};
+use nom::number::complete::{be_i16, be_u16, be_u32, be_u8};
+use nom::IResult;
+
+use crate::notes::Notes;
+use crate::parsers::{Header, Layer, Noteblock, Song};
+use crate::playback;
+
+/// Lowest and highest key the vanilla instruments can play without custom ranges.
+pub const VANILLA_KEY_RANGE: std::ops::RangeInclusive<i8> = 33..=57;
+
+/// MIDI note of the NBS key 0 (A0).
+const KEY_OFFSET: i32 = 21;
+
+const DRUM_CHANNEL: u8 = 9;
+
+const HARP: i8 = 0;
+const DBASS: i8 = 1;
+const BDRUM: i8 = 2;
+const SDRUM: i8 = 3;
+const CLICK: i8 = 4;
+const GUITAR: i8 = 5;
+const FLUTE: i8 = 6;
+const BELL: i8 = 7;
+const ICECHIME: i8 = 8;
+const XYLOBONE: i8 = 9;
+const IRON_XYLOPHONE: i8 = 10;
+const COW_BELL: i8 = 11;
+const DIDGERIDOO: i8 = 12;
+const BIT: i8 = 13;
+const BANJO: i8 = 14;
+const PLING: i8 = 15;
+
+/// The vanilla instrument closest to a General MIDI program.
+pub fn gm_program_instrument(program: u8) -> i8 {
+    match program {
+        0..=7 => HARP, //pianos
+        8 | 9 | 14 => BELL, //celesta, glockenspiel, tubular bells
+        10 => ICECHIME, //music box
+        11 => IRON_XYLOPHONE, //vibraphone
+        12 | 13 => XYLOBONE, //marimba, xylophone
+        15 => BANJO, //dulcimer
+        16..=23 => FLUTE, //organs
+        24..=31 => GUITAR,
+        32..=39 => DBASS,
+        45 | 46 => HARP, //pizzicato, harp
+        47 => BDRUM, //timpani
+        40..=55 => FLUTE, //strings and ensembles
+        56..=63 => BIT, //brass
+        64..=79 => FLUTE, //reeds and pipes
+        80..=87 => BIT, //synth leads
+        88..=103 => PLING, //synth pads and effects
+        104..=106 => BANJO, //sitar, banjo, shamisen
+        107 => HARP, //koto
+        108 => ICECHIME, //kalimba
+        109 => DIDGERIDOO, //bagpipe
+        110 | 111 => FLUTE, //fiddle, shanai
+        112 => BELL, //tinkle bell
+        113 => COW_BELL, //agogo
+        114 => IRON_XYLOPHONE, //steel drums
+        115 => CLICK, //woodblock
+        116..=118 => BDRUM, //taiko, toms, synth drum
+        _ => SDRUM, //reverse cymbal and sound effects
+    }
+}
+
+/// Instrument and key for a note on the General MIDI drum channel.
+pub fn gm_drum_instrument(note: u8) -> (i8, i8) {
+    match note {
+        35 | 36 => (BDRUM, 39), //kicks
+        41 | 43 | 45 | 47 | 48 | 50 => (BDRUM, 39 + (note as i8 - 41)), //toms, low to high
+        38..=40 => (SDRUM, 45), //snares and clap
+        49 | 51 | 52 | 53 | 55 | 57 | 59 => (SDRUM, 52), //cymbals
+        _ => (CLICK, 45), //hi-hats, sticks and the rest
+    }
+}
+
+/// Octaves the instrument's sample sounds above the harp, so imported notes keep their pitch.
+pub fn instrument_octave(instrument: i8) -> i32 {
+    match instrument {
+        DBASS | DIDGERIDOO => -2,
+        GUITAR => -1,
+        FLUTE | COW_BELL => 1,
+        BELL | ICECHIME | XYLOBONE => 2,
+        _ => 0,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImportOptions {
+    /// Tempo of the imported song in ticks per second, the MIDI timing is rounded to these ticks.
+    pub tempo: f64,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions { tempo: 10_f64 }
+    }
+}
+
+/// A note that landed outside [`VANILLA_KEY_RANGE`] and needs custom ranges (or a transpose) to play in Minecraft.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutOfRangeNote {
+    pub tick: i32,
+    pub layer: i32,
+    pub instrument: i8,
+    pub key: i8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MidiImport {
+    pub song: Song,
+    pub out_of_range: Vec<OutOfRangeNote>,
+}
+
+/// Why a MIDI file couldn't be read, with the byte offset from the start of the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MidiError {
+    pub offset: usize,
+    pub expected: &'static str,
+}
+
+impl fmt::Display for MidiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid midi file at byte {}: expected {}", self.offset, self.expected)
+    }
+}
+
+impl std::error::Error for MidiError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MidiEvent {
+    NoteOn { channel: u8, key: u8, velocity: u8 },
+    NoteOff { channel: u8, key: u8 },
+    Controller { channel: u8, controller: u8, value: u8 },
+    Program { channel: u8, program: u8 },
+    PitchBend { channel: u8, value: u16 },
+    /// Microseconds per quarter note.
+    Tempo(u32),
+    TimeSignature { numerator: u8, denominator_power: u8 },
+    TrackName(String),
+    /// Aftertouch, sysex and meta events nothing here uses.
+    Other,
+}
+
+/// An event at its absolute position in MIDI ticks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrackEvent {
+    pub time: u32,
+    pub event: MidiEvent,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MidiFile {
+    pub format: u16,
+    /// Ticks per quarter note, or negative frames per second and ticks per frame for SMPTE timing.
+    pub division: i16,
+    pub tracks: Vec<Vec<TrackEvent>>,
+}
+
+type MidiResult<'a, T> = IResult<&'a [u8], T, (&'a [u8], &'static str)>;
+
+/* turns nom's error into the byte offset and what was wanted */
+fn expect<'a, O>(expected: &'static str, mut parser: impl FnMut(&'a [u8]) -> IResult<&'a [u8], O>) -> impl FnMut(&'a [u8]) -> MidiResult<'a, O> {
+    move |input: &'a [u8]| parser(input).map_err(|error| error.map(|_| (input, expected)))
+}
+
+fn variable_length(input: &[u8]) -> MidiResult<'_, u32> {
+    let mut value: u32 = 0;
+    let mut rest = input;
+    for _ in 0..4 {
+        let (next, byte) = expect("a variable length number", be_u8)(rest)?;
+        rest = next;
+        value = (value << 7) | (byte & 0x7f) as u32;
+        if byte & 0x80 == 0 {
+            return Ok((rest, value));
+        }
+    }
+    Err(nom::Err::Failure((input, "a variable length number of at most 4 bytes")))
+}
+
+fn track(input: &[u8]) -> MidiResult<'_, Vec<TrackEvent>> {
+    let mut events: Vec<TrackEvent> = Vec::new();
+    let mut rest = input;
+    let mut time: u32 = 0;
+    let mut running_status: Option<u8> = None;
+    while !rest.is_empty() {
+        let (next, delta) = variable_length(rest)?;
+        time = time.saturating_add(delta);
+        let (next, first) = expect("an event", be_u8)(next)?;
+        let (next, status, first_data) = if first & 0x80 != 0 {
+            (next, first, None)
+        } else {
+            match running_status {
+                Some(status) => (next, status, Some(first)),
+                None => return Err(nom::Err::Failure((rest, "a status byte"))),
+            }
+        };
+        let channel = status & 0x0f;
+        let (next, event) = match status {
+            0xff => {
+                running_status = None;
+                let (next, kind) = expect("a meta event type", be_u8)(next)?;
+                let (next, length) = variable_length(next)?;
+                let (next, data) = expect("the meta event data", take(length as usize))(next)?;
+                let event = match (kind, data) {
+                    (0x2f, _) => break, //end of track
+                    (0x51, [a, b, c]) => MidiEvent::Tempo(u32::from_be_bytes([0, *a, *b, *c])),
+                    (0x58, [numerator, denominator_power, ..]) => MidiEvent::TimeSignature { numerator: *numerator, denominator_power: *denominator_power },
+                    (0x03, name) => MidiEvent::TrackName(String::from_utf8_lossy(name).into_owned()),
+                    _ => MidiEvent::Other,
+                };
+                (next, event)
+            },
+            0xf0 | 0xf7 => {
+                running_status = None;
+                let (next, length) = variable_length(next)?;
+                let (next, _) = expect("the sysex data", take(length as usize))(next)?;
+                (next, MidiEvent::Other)
+            },
+            0x80..=0xef => {
+                running_status = Some(status);
+                let (next, data1) = match first_data {
+                    Some(data) => (next, data),
+                    None => expect("the event data", be_u8)(next)?,
+                };
+                match status & 0xf0 {
+                    0xc0 => (next, MidiEvent::Program { channel, program: data1 }),
+                    0xd0 => (next, MidiEvent::Other),
+                    kind => {
+                        let (next, data2) = expect("the event data", be_u8)(next)?;
+                        let event = match kind {
+                            0x80 => MidiEvent::NoteOff { channel, key: data1 },
+                            0x90 if data2 == 0 => MidiEvent::NoteOff { channel, key: data1 },
+                            0x90 => MidiEvent::NoteOn { channel, key: data1, velocity: data2 },
+                            0xb0 => MidiEvent::Controller { channel, controller: data1, value: data2 },
+                            0xe0 => MidiEvent::PitchBend { channel, value: (data1 as u16) | ((data2 as u16) << 7) },
+                            _ => MidiEvent::Other, //aftertouch
+                        };
+                        (next, event)
+                    },
+                }
+            },
+            _ => return Err(nom::Err::Failure((rest, "a known status byte"))),
+        };
+        rest = next;
+        events.push(TrackEvent { time, event });
+    }
+    Ok((rest, events))
+}
+
+fn midi_file(input: &[u8]) -> MidiResult<'_, MidiFile> {
+    let (rest, _) = expect("the MThd chunk", tag(b"MThd"))(input)?;
+    let (rest, length) = expect("the header length", be_u32)(rest)?;
+    let (rest, header) = expect("the header", take(length as usize))(rest)?;
+    let (header, format) = expect("the format", be_u16)(header)?;
+    let (header, track_count) = expect("the track count", be_u16)(header)?;
+    let (_, division) = expect("the time division", be_i16)(header)?;
+
+    let mut tracks: Vec<Vec<TrackEvent>> = Vec::new();
+    let mut rest = rest;
+    while tracks.len() < track_count as usize && !rest.is_empty() {
+        let (next, id) = expect("a chunk type", take(4_usize))(rest)?;
+        let (next, length) = expect("the chunk length", be_u32)(next)?;
+        let (next, chunk) = expect("the chunk data", take(length as usize))(next)?;
+        if id == b"MTrk" {
+            tracks.push(track(chunk)?.1);
+        } //other chunks are skipped like the spec asks
+        rest = next;
+    }
+    Ok((rest, MidiFile { format, division, tracks }))
+}
+
+/// Reads a Standard MIDI File.
+pub fn parse(input: &[u8]) -> Result<MidiFile, MidiError> {
+    match midi_file(input) {
+        Ok((_, file)) => Ok(file),
+        Err(nom::Err::Error((rest, expected)) | nom::Err::Failure((rest, expected))) => Err(MidiError {
+            offset: rest.as_ptr() as usize - input.as_ptr() as usize, //track errors point into their chunk
+            expected,
+        }),
+        Err(nom::Err::Incomplete(_)) => Err(MidiError { offset: input.len(), expected: "more data" }),
+    }
+}
+
+impl MidiFile {
+    /// Converts MIDI ticks to seconds using the tempo changes of every track.
+    pub fn seconds(&self) -> impl Fn(u32) -> f64 {
+        let division = self.division;
+        let mut tempos: Vec<(u32, u32)> = self.tracks.iter()
+            .flatten()
+            .filter_map(|event| match event.event {
+                MidiEvent::Tempo(tempo) => Some((event.time, tempo)),
+                _ => None,
+            })
+            .collect();
+        tempos.sort_by_key(|(time, _)| *time);
+        move |time: u32| {
+            if division < 0 {
+                let frames_per_second = -((division >> 8) as f64);
+                let ticks_per_frame = (division & 0xff) as f64;
+                return time as f64 / (frames_per_second * ticks_per_frame);
+            }
+            let ticks_per_quarter = division.max(1) as f64;
+            let mut seconds = 0_f64;
+            let mut last_time: u32 = 0;
+            let mut tempo: u32 = 500000; //120 bpm until told otherwise
+            for (change_time, change_tempo) in &tempos {
+                if *change_time >= time {
+                    break;
+                }
+                seconds += (change_time - last_time) as f64 * tempo as f64 / 1000000_f64 / ticks_per_quarter;
+                last_time = *change_time;
+                tempo = *change_tempo;
+            }
+            seconds + (time - last_time) as f64 * tempo as f64 / 1000000_f64 / ticks_per_quarter
+        }
+    }
+}
+
+/* MIDI pans around 64, note blocks around 100 */
+fn midi_panning(value: u8) -> u8 {
+    if value <= 64 {
+        return (value as u32 * 100 / 64) as u8;
+    }
+    (100 + (value as u32 - 64) * 100 / 63) as u8
+}
+
+/* a track and channel, each gets as many layers as it has notes at once */
+struct Voice {
+    name: String,
+    notes: Vec<(i32, Noteblock)>,
+}
+
+/// Builds a song from a MIDI file: one or more layers per track and channel,
+/// General MIDI programs mapped to the vanilla instruments.
+pub fn import(midi: &MidiFile, options: &ImportOptions) -> MidiImport {
+    let seconds = midi.seconds();
+    let ticks_per_second = if options.tempo > 0_f64 { options.tempo } else { ImportOptions::default().tempo };
+    let mut voices: Vec<Voice> = Vec::new();
+    let mut time_signature: i8 = 4;
+    let mut name = String::new();
+
+    for (track_index, events) in midi.tracks.iter().enumerate() {
+        let mut track_name: Option<String> = None;
+        let mut programs = [0_u8; 16];
+        let mut pannings = [64_u8; 16];
+        let mut track_voices: Vec<(u8, Voice)> = Vec::new();
+        for event in events {
+            match &event.event {
+                MidiEvent::TrackName(track) => {
+                    if track_name.is_none() {
+                        track_name = Some(track.clone());
+                    }
+                    if name.is_empty() && midi.format != 2 && track_index == 0 {
+                        name = track.clone();
+                    }
+                },
+                MidiEvent::TimeSignature { numerator, .. } => time_signature = (*numerator).clamp(2, 8) as i8,
+                MidiEvent::Program { channel, program } => programs[*channel as usize] = *program,
+                MidiEvent::Controller { channel, controller: 10, value } => pannings[*channel as usize] = *value,
+                MidiEvent::NoteOn { channel, key, velocity } => {
+                    let (instrument, nbs_key) = if *channel == DRUM_CHANNEL {
+                        gm_drum_instrument(*key)
+                    } else {
+                        let instrument = gm_program_instrument(programs[*channel as usize]);
+                        let nbs_key = (*key as i32 - KEY_OFFSET - instrument_octave(instrument)*12).clamp(0, 87) as i8;
+                        (instrument, nbs_key)
+                    };
+                    let tick = (seconds(event.time) * ticks_per_second).round() as i32;
+                    let noteblock = Noteblock {
+                        instrument,
+                        key: nbs_key,
+                        volume: ((*velocity as u32 * 100 + 63) / 127) as i8,
+                        panning: midi_panning(pannings[*channel as usize]),
+                        pitch: 0,
+                    };
+                    let voice = match track_voices.iter().position(|(voice_channel, _)| voice_channel == channel) {
+                        Some(index) => &mut track_voices[index].1,
+                        None => {
+                            track_voices.push((*channel, Voice { name: String::new(), notes: Vec::new() }));
+                            &mut track_voices.last_mut().unwrap().1
+                        },
+                    };
+                    voice.notes.push((tick, noteblock));
+                },
+                _ => {},
+            }
+        }
+        let several_channels = track_voices.len() > 1;
+        for (channel, mut voice) in track_voices {
+            voice.name = track_name.clone().unwrap_or_else(|| format!("Track {}", track_index+1));
+            if several_channels {
+                voice.name = format!("{} ch{}", voice.name, channel+1);
+            }
+            voices.push(voice);
+        }
+    }
+
+    let mut noteblocks = Notes::new();
+    let mut layers: Vec<Layer> = Vec::new();
+    let mut out_of_range: Vec<OutOfRangeNote> = Vec::new();
+    for voice in voices {
+        let first_layer = layers.len() as i32;
+        let mut voice_layers: i32 = 0;
+        for (tick, noteblock) in voice.notes {
+            //chords spill onto the voice's next layers
+            let mut layer = first_layer;
+            while noteblocks.get(tick, layer).is_some() {
+                layer += 1;
+            }
+            voice_layers = voice_layers.max(layer - first_layer + 1);
+            if !VANILLA_KEY_RANGE.contains(&noteblock.key) {
+                out_of_range.push(OutOfRangeNote { tick, layer, instrument: noteblock.instrument, key: noteblock.key });
+            }
+            noteblocks.insert(tick, layer, noteblock);
+        }
+        for index in 0..voice_layers {
+            layers.push(Layer {
+                name: if index == 0 { voice.name.clone() } else { format!("{} {}", voice.name, index+1) },
+                locked: 0,
+                volume: 100,
+                stereo: 100,
+            });
+        }
+    }
+    out_of_range.sort_by_key(|note| (note.tick, note.layer));
+
+    let song = Song {
+        header: Header {
+            open_nbs_version: 5,
+            vanilla_instrument_count: 16,
+            song_length: noteblocks.last_tick().unwrap_or(0) as i16,
+            layer_count: layers.len() as i16,
+            name,
+            author: String::new(),
+            orig_author: String::new(),
+            description: String::new(),
+            tempo: (ticks_per_second * 100_f64).round() as i16,
+            auto_save: 0,
+            auto_save_period: 10,
+            time_signature,
+            minutes_spent: 0,
+            left_clicks: 0,
+            right_clicks: 0,
+            noteblocks_added: noteblocks.len() as i32,
+            noteblocks_removed: 0,
+            original_file_name: String::new(),
+            looping: 0,
+            loop_count: 0,
+            loop_start_tick: 0,
+        },
+        noteblocks,
+        layers,
+        custom_instruments: Vec::new(),
+    };
+    MidiImport { song, out_of_range }
+}
+
+/// Reads a MIDI file and imports it, `file_name` is kept as the song's original file name.
+pub fn import_file(input: &[u8], file_name: &str, options: &ImportOptions) -> Result<MidiImport, MidiError> {
+    let mut import = import(&parse(input)?, options);
+    import.song.header.original_file_name = file_name.to_owned();
+    Ok(import)
+}
+
+/// MIDI ticks per quarter note of exported files, a quarter note is 4 song ticks.
+pub const EXPORT_TICKS_PER_QUARTER: u16 = 480;
+
+const EXPORT_TICKS_PER_TICK: u32 = EXPORT_TICKS_PER_QUARTER as u32 / 4;
+
+/// The General MIDI program for a vanilla instrument, or the drum note for the percussion ones.
+pub fn instrument_gm_program(instrument: i8) -> (u8, bool) {
+    match instrument {
+        DBASS => (32, false),
+        BDRUM => (36, true),
+        SDRUM => (38, true),
+        CLICK => (42, true),
+        GUITAR => (24, false),
+        FLUTE => (73, false),
+        BELL => (9, false),
+        ICECHIME => (10, false),
+        XYLOBONE => (13, false),
+        IRON_XYLOPHONE => (11, false),
+        COW_BELL => (113, false),
+        DIDGERIDOO => (109, false),
+        BIT => (80, false),
+        BANJO => (105, false),
+        PLING => (88, false),
+        _ => (0, false), //harp and custom instruments
+    }
+}
+
+fn write_variable_length(output: &mut Vec<u8>, value: u32) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.reverse();
+    output.extend(bytes);
+}
+
+/* microseconds per quarter note for a tick length in seconds */
+fn tempo_event(tick_length: f64) -> Vec<u8> {
+    let tempo = ((tick_length * 4_f64 * 1000000_f64).round() as u32).clamp(1, 0xffffff);
+    let bytes = tempo.to_be_bytes();
+    vec![0xff, 0x51, 3, bytes[1], bytes[2], bytes[3]]
+}
+
+fn meta_text(kind: u8, text: &str) -> Vec<u8> {
+    let mut event = vec![0xff, kind];
+    write_variable_length(&mut event, text.len() as u32);
+    event.extend(text.as_bytes());
+    event
+}
+
+/* note blocks pan 0 to 200 around 100, MIDI 0 to 127 around 64 */
+fn nbs_panning(panning: u8) -> u8 {
+    if panning <= 100 {
+        return (panning as u32 * 64 / 100) as u8;
+    }
+    (64 + (panning.min(200) as u32 - 100) * 63 / 100) as u8
+}
+
+/* events are (time, order, bytes), note offs sort before anything else at the same time */
+fn track_chunk(output: &mut Vec<u8>, mut events: Vec<(u32, u8, Vec<u8>)>) {
+    events.sort_by_key(|(time, order, _)| (*time, *order));
+    let mut data: Vec<u8> = Vec::new();
+    let mut last_time: u32 = 0;
+    for (time, _, event) in events {
+        write_variable_length(&mut data, time - last_time);
+        data.extend(event);
+        last_time = time;
+    }
+    write_variable_length(&mut data, 0);
+    data.extend([0xff, 0x2f, 0]);
+    output.extend(b"MTrk");
+    output.extend((data.len() as u32).to_be_bytes());
+    output.extend(data);
+}
+
+/* what a channel was last set to, notes of another layer on it set it again when theirs differ */
+#[derive(Clone, Copy, Default)]
+struct ChannelState {
+    program: Option<u8>,
+    volume: Option<u8>,
+    panning: Option<u8>,
+    bend: Option<u16>,
+}
+
+/// Writes the song as a format 1 MIDI file: a tempo track followed by one track per layer.
+///
+/// Each layer gets its own channel so panning and pitch bend apply to its notes only,
+/// layers past the 15th share channels again. Drums always go to channel 10. A channel's
+/// program, volume, panning and pitch bend are set again whenever a note needs different ones,
+/// so layers sharing it don't change each other's notes.
+/// Fine pitch is bent with the default range of 2 semitones, whole semitones move the note.
+pub fn export(song: &Song) -> Vec<u8> {
+    let instruments = playback::instruments(song);
+    let tempo_changer = playback::tempo_changer_index(&instruments);
+    let layers = playback::layers(song);
+    let layer_count = song.noteblocks.iter()
+        .map(|(_, layer, _)| layer + 1)
+        .max()
+        .unwrap_or(0)
+        .max(layers.len() as i32);
+
+    let mut output: Vec<u8> = Vec::new();
+    output.extend(b"MThd");
+    output.extend(6_u32.to_be_bytes());
+    output.extend(1_u16.to_be_bytes());
+    output.extend((layer_count as u16 + 1).to_be_bytes());
+    output.extend(EXPORT_TICKS_PER_QUARTER.to_be_bytes());
+
+    //tempo track
+    let mut events: Vec<(u32, u8, Vec<u8>)> = Vec::new();
+    if !song.header.name.is_empty() {
+        events.push((0, 0, meta_text(0x03, &song.header.name)));
+    }
+    let numerator = song.header.time_signature.clamp(1, 127) as u8;
+    events.push((0, 0, vec![0xff, 0x58, 4, numerator, 2, 24, 8]));
+    events.push((0, 0, tempo_event(100_f64 / song.header.tempo.max(1) as f64)));
+    for (tick, _, noteblock) in song.noteblocks.iter() {
+        if noteblock.instrument == tempo_changer && noteblock.pitch > 0 {
+            events.push((tick.max(0) as u32 * EXPORT_TICKS_PER_TICK, 1, tempo_event(15_f64 / noteblock.pitch as f64)));
+        }
+    }
+    track_chunk(&mut output, events);
+
+    let mut tracks: Vec<Vec<(u32, u8, Vec<u8>)>> = (0..layer_count as usize).map(|layer| match layers.get(layer) {
+        Some(layer_entry) if !layer_entry.name.is_empty() => vec![(0, 1, meta_text(0x03, &layer_entry.name))],
+        _ => Vec::new(),
+    }).collect();
+    let mut channels = [ChannelState::default(); 16];
+    for (tick, layer, noteblock) in song.noteblocks.iter() {
+        if noteblock.instrument == tempo_changer || noteblock.volume <= 0 || layer < 0 {
+            continue;
+        }
+        let Some(instrument) = instruments.get(noteblock.instrument as usize) else {
+            continue;
+        };
+        let events = &mut tracks[layer as usize];
+        let time = tick.max(0) as u32 * EXPORT_TICKS_PER_TICK;
+        let velocity = (noteblock.volume.min(100) as u32 * 127 / 100).max(1) as u8;
+        let volume = layers.get(layer as usize).map_or(127, |layer_entry| (layer_entry.volume.clamp(0, 100) as u32 * 127 / 100) as u8);
+        let note_panning = nbs_panning(noteblock.panning);
+        let is_vanilla = (noteblock.instrument as i16) < song.header.vanilla_instrument_count as i16;
+        let (gm, drum) = if is_vanilla { instrument_gm_program(noteblock.instrument) } else { (0, false) };
+        let channel = if drum {
+            DRUM_CHANNEL
+        } else {
+            match layer % 15 {
+                channel if channel >= DRUM_CHANNEL as i32 => channel as u8 + 1,
+                channel => channel as u8,
+            }
+        };
+        let state = &mut channels[channel as usize];
+        if state.volume != Some(volume) {
+            events.push((time, 1, vec![0xb0 | channel, 7, volume]));
+            state.volume = Some(volume);
+        }
+        if state.panning != Some(note_panning) {
+            events.push((time, 1, vec![0xb0 | channel, 10, note_panning]));
+            state.panning = Some(note_panning);
+        }
+        if drum {
+            events.push((time, 2, vec![0x90 | channel, gm, velocity]));
+            events.push((time + EXPORT_TICKS_PER_TICK, 0, vec![0x80 | channel, gm, 0]));
+            continue;
+        }
+        if state.program != Some(gm) {
+            events.push((time, 1, vec![0xc0 | channel, gm]));
+            state.program = Some(gm);
+        }
+        //custom samples sound at their own key, vanilla ones an octave or two off the harp
+        let octave = if is_vanilla { instrument_octave(noteblock.instrument) } else { 0 };
+        let semitones = (noteblock.pitch as f64 / 100_f64).round() as i32;
+        let note_bend = (8192 + (noteblock.pitch as i32 - semitones * 100) * 8192 / 200).clamp(0, 16383) as u16;
+        let note = (noteblock.key as i32 + 45 - instrument.sound_key as i32 + KEY_OFFSET + octave * 12 + semitones).clamp(0, 127) as u8;
+        if state.bend != Some(note_bend) {
+            events.push((time, 1, vec![0xe0 | channel, (note_bend & 0x7f) as u8, (note_bend >> 7) as u8]));
+            state.bend = Some(note_bend);
+        }
+        events.push((time, 2, vec![0x90 | channel, note, velocity]));
+        events.push((time + EXPORT_TICKS_PER_TICK, 0, vec![0x80 | channel, note, 0]));
+    }
+    for events in tracks {
+        track_chunk(&mut output, events);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::tests::empty_song;
+
+    /* the program and panning each note on starts with, going through the tracks the way a player merges them */
+    fn note_states(midi: &MidiFile) -> Vec<(u32, u8, Option<u8>, Option<u8>)> {
+        let mut events: Vec<&TrackEvent> = midi.tracks.iter().flatten().collect();
+        events.sort_by_key(|event| event.time);
+        let mut programs = [None; 16];
+        let mut pannings = [None; 16];
+        let mut notes = Vec::new();
+        for event in events {
+            match event.event {
+                MidiEvent::Program { channel, program } => programs[channel as usize] = Some(program),
+                MidiEvent::Controller { channel, controller: 10, value } => pannings[channel as usize] = Some(value),
+                MidiEvent::NoteOn { channel, velocity, .. } if velocity > 0 => notes.push((event.time, channel, programs[channel as usize], pannings[channel as usize])),
+                _ => {},
+            }
+        }
+        notes
+    }
+
+    /* two tracks at 480 ticks per quarter: the tempo map, then guitar chords and a kick on one track */
+    const SMF: &[u8] = &[
+        b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 1, 0, 2, 0x01, 0xe0,
+        b'M', b'T', b'r', b'k', 0, 0, 0, 35,
+        0x00, 0xff, 0x03, 4, b'T', b'u', b'n', b'e',
+        0x00, 0xff, 0x58, 4, 3, 2, 24, 8, //3/4
+        0x00, 0xff, 0x51, 3, 0x0f, 0x42, 0x40, //a second per quarter
+        0x87, 0x40, 0xff, 0x51, 3, 0x07, 0xa1, 0x20, //half a second from tick 960 on
+        0x00, 0xff, 0x2f, 0,
+        b'M', b'T', b'r', b'k', 0, 0, 0, 41,
+        0x00, 0xff, 0x03, 5, b'P', b'i', b'a', b'n', b'o',
+        0x00, 0xc0, 24, //nylon guitar
+        0x00, 0x90, 60, 100,
+        0x00, 64, 100, //running status
+        0x00, 0x99, 36, 127, //kick on the drum channel
+        0x83, 0x60, 0x80, 60, 0,
+        0x00, 0x90, 108, 64, //far above the guitar's range
+        0x87, 0x40, 0x90, 62, 100,
+        0x00, 0xff, 0x2f, 0,
+    ];
+
+    #[test]
+    fn parses_events_and_running_status() {
+        let midi = parse(SMF).unwrap();
+        assert_eq!((midi.format, midi.division, midi.tracks.len()), (1, 480, 2));
+        let event = |time: u32, event: MidiEvent| TrackEvent { time, event };
+        assert_eq!(midi.tracks[0], vec![
+            event(0, MidiEvent::TrackName("Tune".to_owned())),
+            event(0, MidiEvent::TimeSignature { numerator: 3, denominator_power: 2 }),
+            event(0, MidiEvent::Tempo(1000000)),
+            event(960, MidiEvent::Tempo(500000)),
+        ]);
+        assert_eq!(midi.tracks[1], vec![
+            event(0, MidiEvent::TrackName("Piano".to_owned())),
+            event(0, MidiEvent::Program { channel: 0, program: 24 }),
+            event(0, MidiEvent::NoteOn { channel: 0, key: 60, velocity: 100 }),
+            event(0, MidiEvent::NoteOn { channel: 0, key: 64, velocity: 100 }),
+            event(0, MidiEvent::NoteOn { channel: 9, key: 36, velocity: 127 }),
+            event(480, MidiEvent::NoteOff { channel: 0, key: 60 }),
+            event(480, MidiEvent::NoteOn { channel: 0, key: 108, velocity: 64 }),
+            event(1440, MidiEvent::NoteOn { channel: 0, key: 62, velocity: 100 }),
+        ]);
+        let seconds = midi.seconds();
+        assert_eq!((seconds(480), seconds(960), seconds(1440)), (1_f64, 2_f64, 2.5));
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_byte() {
+        assert_eq!(parse(b"RIFF"), Err(MidiError { offset: 0, expected: "the MThd chunk" }));
+        let mut bytes = SMF.to_vec();
+        bytes[23] = 0x40; //data where the first event's status should be, the error points at the event
+        assert_eq!(parse(&bytes), Err(MidiError { offset: 22, expected: "a status byte" }));
+    }
+
+    #[test]
+    fn imports_programs_drums_and_chords() {
+        let import = import(&parse(SMF).unwrap(), &ImportOptions::default());
+        let song = &import.song;
+        assert_eq!((song.header.name.as_str(), song.header.tempo, song.header.time_signature), ("Tune", 1000, 3));
+        assert_eq!(song.layers.iter().map(|layer| layer.name.as_str()).collect::<Vec<_>>(), vec!["Piano ch1", "Piano ch1 2", "Piano ch10"]);
+        let note = |instrument: i8, key: i8, volume: i8| Noteblock { instrument, key, volume, panning: 100, pitch: 0 };
+        assert_eq!(song.noteblocks.iter().map(|(tick, layer, noteblock)| (tick, layer, noteblock.clone())).collect::<Vec<_>>(), vec![
+            (0, 0, note(GUITAR, 51, 79)), //an octave up, the guitar sounds an octave below the harp
+            (0, 1, note(GUITAR, 55, 79)), //the chord's second note on the next layer
+            (0, 2, note(BDRUM, 39, 100)),
+            (10, 0, note(GUITAR, 87, 50)),
+            (25, 0, note(GUITAR, 53, 79)), //after the tempo doubles
+        ]);
+        assert_eq!(import.out_of_range, vec![OutOfRangeNote { tick: 10, layer: 0, instrument: GUITAR, key: 87 }]);
+    }
+
+    #[test]
+    fn imports_at_the_tempo_asked_for() {
+        let song = import(&parse(SMF).unwrap(), &ImportOptions { tempo: 20_f64 }).song;
+        assert_eq!(song.header.tempo, 2000);
+        assert_eq!(song.noteblocks.ticks(..).map(|(tick, _)| tick).collect::<Vec<_>>(), vec![0, 20, 50]);
+    }
+
+    #[test]
+    fn layers_sharing_a_channel_keep_their_program_and_panning() {
+        let mut song = empty_song(17);
+        let note = |instrument: i8, panning: u8| Noteblock { instrument, key: 45, volume: 100, panning, pitch: 0 };
+        //layers 0 and 15 both end up on channel 1, every drum on channel 10
+        for tick in [0, 2, 4] {
+            song.noteblocks.insert(tick, 0, note(HARP, 50));
+            song.noteblocks.insert(tick + 1, 15, note(DBASS, 150));
+            song.noteblocks.insert(tick, 1, note(BDRUM, 0));
+            song.noteblocks.insert(tick + 1, 16, note(SDRUM, 200));
+        }
+        let midi = parse(&export(&song)).unwrap();
+        assert_eq!(midi.tracks.len(), 18);
+
+        let notes = note_states(&midi);
+        assert_eq!(notes.len(), 12);
+        for (time, channel, program, panning) in notes {
+            let first = (time / EXPORT_TICKS_PER_TICK).is_multiple_of(2);
+            if channel == DRUM_CHANNEL {
+                assert_eq!(panning, Some(nbs_panning(if first { 0 } else { 200 })), "drums at {}", time);
+            } else {
+                assert_eq!(channel, 0);
+                let instrument = if first { HARP } else { DBASS };
+                assert_eq!(program, Some(instrument_gm_program(instrument).0), "program at {}", time);
+                assert_eq!(panning, Some(nbs_panning(if first { 50 } else { 150 })), "panning at {}", time);
+            }
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use ratatui::{widgets::{StatefulWidget}, style::{Style, Color}, layout::Rect, buffer::{Buffer, Cell}};
 
-use crate::{editor::EditorState, parsers::{NoteblockSection}};
+use crate::editor::EditorState;
 #[derive(Debug)]
 pub struct NoteblockWidget {
     /// Type of the border. The default is plain lines but one can choose to have rounded corners
@@ -64,77 +64,72 @@ impl StatefulWidget for NoteblockWidget {
         // buf.set_style(area, self.style);
         let inner_style = Style::default().fg(Color::White);
 
-        let mut tick: i32 = editor_state.prev_tick as i32;
-        let mut layer: u16 = 0;
-        
-        for index in editor_state.prev_index as usize ..editor_state.song.as_mut().unwrap().noteblocks.len(){
-            match &editor_state.song.as_mut().unwrap().noteblocks[index]{
-                NoteblockSection::SetTick(num) => {
-                    tick = *num as i32;
-                    // if ((tick as f32 - editor_state.tick) * self.block_width as f32) as u16 >= area.right() {
-                    //     break;
-                    // }
-                },
-                NoteblockSection::SetLayer(num) => {
-                    layer = *num as u16;
-                },
-                NoteblockSection::Noteblock(noteblock) => {
-                    if (layer as u16+1) * self.block_height <= area.bottom() {
-                        let real_x: u16 = ((tick as f32-editor_state.tick)*self.block_width as f32).floor() as u16;
-                        let real_y: u16 = layer *self.block_height;
-                        if real_y+self.block_height+1 >=area.bottom() || 
-                            real_y < area.top() ||
-                            real_x+self.block_width+1 >= area.right() ||
-                            real_x < area.left() 
-                        {
-                            continue;
-                        }
-
-                        let border_style = Style::default().fg(get_instrument_color(noteblock.instrument));
+        let visible_ticks = (area.width / self.block_width) as i32 + 1;
+        let last_tick = editor_state.tick.floor() as i32 + visible_ticks;
+        let song = editor_state.song.as_ref().unwrap();
+
+        for (tick, layer, noteblock) in song.noteblocks.range(editor_state.prev_tick..=last_tick) {
+            let layer = layer as u16;
+            if (layer+1) * self.block_height <= area.bottom() {
+                let real_x: u16 = ((tick as f32-editor_state.tick)*self.block_width as f32).floor() as u16;
+                let real_y: u16 = layer *self.block_height;
+                if real_y+self.block_height+1 >=area.bottom() || 
+                    real_y < area.top() ||
+                    real_x+self.block_width+1 >= area.right() ||
+                    real_x < area.left() 
+                {
+                    continue;
+                }
+
+                let border_style = Style::default().fg(get_instrument_color(noteblock.instrument));
             
-                        for num in 1..self.block_width {
-                            add_to_cell(buf,real_x+num,real_y,HORI,&border_style);
-                            add_to_cell(buf,real_x+num,real_y+self.block_height,HORI,&border_style);
-                        }
-                        for num in 1..self.block_height {
-                            add_to_cell(buf,real_x,real_y+num,VERT,&border_style);
-                            add_to_cell(buf,real_x+self.block_width,real_y+num,VERT,&border_style);
-                        }
+                for num in 1..self.block_width {
+                    add_to_cell(buf,real_x+num,real_y,HORI,&border_style);
+                    add_to_cell(buf,real_x+num,real_y+self.block_height,HORI,&border_style);
+                }
+                for num in 1..self.block_height {
+                    add_to_cell(buf,real_x,real_y+num,VERT,&border_style);
+                    add_to_cell(buf,real_x+self.block_width,real_y+num,VERT,&border_style);
+                }
             
-                        
-                        add_to_cell(buf,real_x,real_y,RIGHT_DOWN,&border_style);
-                        add_to_cell(buf,real_x+self.block_width,real_y,LEFT_DOWN,&border_style);
-                        add_to_cell(buf,real_x,real_y+self.block_height,RIGHT_UP,&border_style);
-                        add_to_cell(buf,real_x+self.block_width,real_y+self.block_height,LEFT_UP,&border_style);
             
-                        let mut key : String;
-                        if noteblock.key-33 < 0 {
-                            key = "<".to_string();
-                        } else if noteblock.key-33 > 57{
-                            key = ">".to_string();
-                        } else {
-                            key = (noteblock.key-33).to_string();
-                        }
-                        if key.len() == 1 {
-                            key = format!(" {}", key);
-                        }
-                        
+                add_to_cell(buf,real_x,real_y,RIGHT_DOWN,&border_style);
+                add_to_cell(buf,real_x+self.block_width,real_y,LEFT_DOWN,&border_style);
+                add_to_cell(buf,real_x,real_y+self.block_height,RIGHT_UP,&border_style);
+                add_to_cell(buf,real_x+self.block_width,real_y+self.block_height,LEFT_UP,&border_style);
             
-                        
-                        buf.get_mut(real_x+1,real_y+1)
-                        .set_char(key.chars().nth(0).unwrap())
-                        .set_style(inner_style);
-                        buf.get_mut(real_x+2,real_y+1)
-                        .set_char(key.chars().nth(1).unwrap())
-                        .set_style(inner_style);
-                        buf.get_mut(real_x+3,real_y+1)
-                        .set_symbol(" ")
-                        .set_style(inner_style);
-                    }
-                },
+                let mut key : String;
+                if noteblock.key-33 < 0 {
+                    key = "<".to_string();
+                } else if noteblock.key-33 > 57{
+                    key = ">".to_string();
+                } else {
+                    key = (noteblock.key-33).to_string();
+                }
+                if key.len() == 1 {
+                    key = format!(" {}", key);
+                }
+            
+            
+            
+                buf.get_mut(real_x+1,real_y+1)
+                .set_char(key.chars().nth(0).unwrap())
+                .set_style(inner_style);
+                buf.get_mut(real_x+2,real_y+1)
+                .set_char(key.chars().nth(1).unwrap())
+                .set_style(inner_style);
+                buf.get_mut(real_x+3,real_y+1)
+                .set_symbol(" ")
+                .set_style(inner_style);
             }
         }
 
+        //the column playback starts from when playing from the cursor
+        let cursor_x = (editor_state.cursor as f32-editor_state.tick)*self.block_width as f32;
+        if cursor_x >= area.left() as f32 && cursor_x + self.block_width as f32 + 1_f32 <= area.right() as f32 {
+            buf.set_style(Rect::new(cursor_x.floor() as u16, area.top(), self.block_width+1, area.height), Style::default().bg(Color::DarkGray));
+        }
+
         // for block in self.blocks {
 
         // }
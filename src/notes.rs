@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+
+use crate::parsers::{Noteblock, NoteblockSection};
+
+/// The note blocks of a song, addressed by tick and layer and kept in tick order.
+///
+/// Ticks without any note blocks are kept when they come from a file, so the
+/// wire format round-trips, but removing the last note of a tick drops the tick.
+/// Serialized it's a list of note blocks with their tick and layer, without the empty ticks.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(into = "Vec<PlacedNoteblock>", try_from = "Vec<PlacedNoteblock>"))]
+pub struct Notes {
+    ticks: BTreeMap<i32, BTreeMap<i32, Noteblock>>,
+    len: usize,
+}
+
+impl Notes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Amount of note blocks (not ticks).
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, tick: i32, layer: i32) -> Option<&Noteblock> {
+        self.ticks.get(&tick)?.get(&layer)
+    }
+
+    pub fn get_mut(&mut self, tick: i32, layer: i32) -> Option<&mut Noteblock> {
+        self.ticks.get_mut(&tick)?.get_mut(&layer)
+    }
+
+    /// Puts a note block at the position, returning the one it replaced.
+    pub fn insert(&mut self, tick: i32, layer: i32, noteblock: Noteblock) -> Option<Noteblock> {
+        let replaced = self.ticks.entry(tick).or_default().insert(layer, noteblock);
+        if replaced.is_none() {
+            self.len += 1;
+        }
+        replaced
+    }
+
+    /// Keeps a tick even without note blocks, like ticks read from a file.
+    pub fn insert_tick(&mut self, tick: i32) {
+        self.ticks.entry(tick).or_default();
+    }
+
+    pub fn remove(&mut self, tick: i32, layer: i32) -> Option<Noteblock> {
+        let layers = self.ticks.get_mut(&tick)?;
+        let removed = layers.remove(&layer)?;
+        if layers.is_empty() {
+            self.ticks.remove(&tick);
+        }
+        self.len -= 1;
+        Some(removed)
+    }
+
+    /// Keeps only the note blocks the predicate returns true for, returns how many were removed.
+    /// Ticks left without note blocks go, like with [`Notes::remove`].
+    pub fn retain(&mut self, mut keep: impl FnMut(i32, i32, &Noteblock) -> bool) -> usize {
+        let before = self.len;
+        self.ticks.retain(|tick, layers| {
+            if layers.is_empty() {
+                return true; //empty ticks from a file stay
+            }
+            layers.retain(|layer, noteblock| keep(*tick, *layer, noteblock));
+            !layers.is_empty()
+        });
+        self.len = self.ticks.values().map(BTreeMap::len).sum();
+        before - self.len
+    }
+
+    /// The note blocks of one tick by layer.
+    pub fn tick(&self, tick: i32) -> Option<&BTreeMap<i32, Noteblock>> {
+        self.ticks.get(&tick)
+    }
+
+    /// Every tick in the range with its note blocks by layer.
+    pub fn ticks(&self, range: impl RangeBounds<i32>) -> impl DoubleEndedIterator<Item = (i32, &BTreeMap<i32, Noteblock>)> {
+        self.ticks.range(range).map(|(tick, layers)| (*tick, layers))
+    }
+
+    /// Every note block in the tick range as (tick, layer, note block).
+    pub fn range(&self, range: impl RangeBounds<i32>) -> impl Iterator<Item = (i32, i32, &Noteblock)> {
+        self.ticks(range).flat_map(|(tick, layers)| {
+            layers.iter().map(move |(layer, noteblock)| (tick, *layer, noteblock))
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (i32, i32, &Noteblock)> {
+        self.range(..)
+    }
+
+    /// The first tick after `tick` that is in the song.
+    pub fn next_tick(&self, tick: i32) -> Option<i32> {
+        self.ticks.range(tick + 1..).next().map(|(tick, _)| *tick)
+    }
+
+    pub fn first_tick(&self) -> Option<i32> {
+        self.ticks.keys().next().copied()
+    }
+
+    pub fn last_tick(&self) -> Option<i32> {
+        self.ticks.keys().next_back().copied()
+    }
+
+    /// Builds the model from the absolute SetTick/SetLayer stream the parser reads.
+    pub fn from_sections(sections: &[NoteblockSection]) -> Notes {
+        let mut notes = Notes::new();
+        let mut tick: i32 = -1;
+        let mut layer: i32 = -1;
+        for section in sections {
+            match section {
+                NoteblockSection::SetTick(num) => {
+                    tick = *num;
+                    notes.insert_tick(tick);
+                },
+                NoteblockSection::SetLayer(num) => layer = *num,
+                NoteblockSection::Noteblock(noteblock) => {
+                    notes.insert(tick, layer, noteblock.clone());
+                },
+            }
+        }
+        notes
+    }
+
+    /// The SetTick/SetLayer stream the writer stores, in tick and layer order.
+    pub fn to_sections(&self) -> Vec<NoteblockSection> {
+        let mut sections: Vec<NoteblockSection> = Vec::with_capacity(self.ticks.len() + self.len * 2);
+        for (tick, layers) in &self.ticks {
+            sections.push(NoteblockSection::SetTick(*tick));
+            for (layer, noteblock) in layers {
+                sections.push(NoteblockSection::SetLayer(*layer));
+                sections.push(NoteblockSection::Noteblock(noteblock.clone()));
+            }
+        }
+        sections
+    }
+}
+
+/// A note block with its position, the way [`Notes`] serialize.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PlacedNoteblock {
+    pub tick: i32,
+    pub layer: i32,
+    #[serde(flatten)]
+    pub noteblock: Noteblock,
+}
+
+#[cfg(feature = "serde")]
+impl From<Notes> for Vec<PlacedNoteblock> {
+    fn from(notes: Notes) -> Self {
+        notes.iter()
+            .map(|(tick, layer, noteblock)| PlacedNoteblock { tick, layer, noteblock: noteblock.clone() })
+            .collect()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<Vec<PlacedNoteblock>> for Notes {
+    type Error = String;
+
+    fn try_from(placed: Vec<PlacedNoteblock>) -> Result<Self, Self::Error> {
+        let mut notes = Notes::new();
+        for PlacedNoteblock { tick, layer, noteblock } in placed {
+            if notes.insert(tick, layer, noteblock).is_some() {
+                return Err(format!("two note blocks at tick {} on layer {}", tick, layer));
+            }
+        }
+        Ok(notes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(key: i8) -> Noteblock {
+        Noteblock { instrument: 0, key, volume: 100, panning: 100, pitch: 0 }
+    }
+
+    #[test]
+    fn retain_drops_ticks_it_empties() {
+        let mut notes = Notes::new();
+        notes.insert(0, 0, note(40));
+        notes.insert(4, 0, note(45));
+        notes.insert(4, 1, note(50));
+        notes.insert(9, 2, note(45));
+        notes.insert_tick(12);
+        assert_eq!(notes.retain(|_, _, noteblock| noteblock.key != 45), 2);
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes.ticks(..).map(|(tick, _)| tick).collect::<Vec<_>>(), vec![0, 4, 12]);
+        assert_eq!(notes.retain(|tick, _, _| tick == 0), 1);
+        assert_eq!(notes.ticks(..).map(|(tick, _)| tick).collect::<Vec<_>>(), vec![0, 12]);
+    }
+}
@@ -1,15 +1,24 @@
-use nom::bytes::streaming::take;
-use nom::number::streaming::*;
+use std::fmt;
+
+use nom::bytes::complete::take;
+use nom::number::complete::*;
 use nom::{
-    combinator::map_res,
+    error::ErrorKind,
+    Err::{Error, Failure},
     IResult,
 };
 use NoteblockSection::{SetTick, SetLayer};
 
+use crate::notes::Notes;
+
+/// Vanilla instruments available before the open nbs versions added the count to the header.
+pub const CLASSIC_INSTRUMENT_COUNT: i8 = 10;
+
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Header {
-    pub open_nbs_version: i8,
-    pub vanilla_instrument_count: i8,
+    pub open_nbs_version: i8, // NBS version				0 for classic Note Block Studio files, which have no version field.
+    pub vanilla_instrument_count: i8, // Vanilla instrument count	Always 10 in classic files.
     pub song_length: i16,
     pub layer_count: i16, // Layer count				The last layer with at least one note block in it, or the last layer that has had its name, volume or stereo changed.
     pub name: String,     // Song name				The name of the song.
@@ -38,6 +47,7 @@ pub struct Header {
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Noteblock {
     pub instrument: i8,
     pub key: i8,
@@ -46,6 +56,7 @@ pub struct Noteblock {
     pub pitch: i16,
 }
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Layer {
     pub name: String,
     pub locked: i8,
@@ -53,6 +64,7 @@ pub struct Layer {
     pub stereo: u8,
 }
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Instrument {
     pub name: String,
     pub sound_file: String,
@@ -60,42 +72,59 @@ pub struct Instrument {
     pub press_key: i8, //0,1
 }
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Song {
     pub header: Header,
-    pub noteblocks: Vec<NoteblockSection>,
+    pub noteblocks: Notes,
     pub layers: Vec<Layer>,
     pub custom_instruments: Vec<Instrument>,
 }
 
-pub fn header(input: &[u8]) -> IResult<&[u8], Header> {
-    let (input, _is_open_nbs) = le_i16(input)?;
-	//shigsplode when is_open_nbs is not 0
-    let (input, open_nbs_version) = le_i8(input)?;
-    let (input, vanilla_instrument_count) = le_i8(input)?;
-    let (input, song_length) = le_i16(input)?;
-    let (input, layer_count) = le_i16(input)?;
-    let (input, string_length) = le_i32(input)?;
-    let (input, name) = map_res(take(string_length as usize), std::str::from_utf8)(input)?;
-    let (input, string_length) = le_i32(input)?;
-    let (input, author) = map_res(take(string_length as usize), std::str::from_utf8)(input)?;
-    let (input, string_length) = le_i32(input)?;
-    let (input, orig_author) = map_res(take(string_length as usize), std::str::from_utf8)(input)?;
-    let (input, string_length) = le_i32(input)?;
-    let (input, description) = map_res(take(string_length as usize), std::str::from_utf8)(input)?;
-    let (input, tempo) = le_i16(input)?;
-    let (input, auto_save) = le_i8(input)?;
-    let (input, auto_save_period) = le_i8(input)?;
-    let (input, time_signature) = le_i8(input)?;
-    let (input, minutes_spent) = le_i32(input)?;
-    let (input, left_clicks) = le_i32(input)?;
-    let (input, right_clicks) = le_i32(input)?;
-    let (input, noteblocks_added) = le_i32(input)?;
-    let (input, noteblocks_removed) = le_i32(input)?;
-    let (input, string_length) = le_i32(input)?;
-    let (input, original_file_name) = map_res(take(string_length as usize), std::str::from_utf8)(input)?;
-    let (input, looping) = le_i8(input)?;
-    let (input, loop_count) = le_i8(input)?;
-    let (input, loop_start_tick) = le_i16(input)?;
+impl Song {
+    /// Vanilla plus custom instruments, note blocks use indices below this.
+    pub fn instrument_count(&self) -> i16 {
+        self.header.vanilla_instrument_count as i16 + self.custom_instruments.len() as i16
+    }
+}
+
+/* the first short is the song length in classic files and 0 in open nbs ones */
+pub fn header(input: &[u8]) -> NbsResult<'_, Header> {
+    let (input, classic_song_length) = field("song length", le_i16)(input)?;
+    let (input, open_nbs_version, vanilla_instrument_count, song_length) = if classic_song_length != 0 {
+        (input, 0, CLASSIC_INSTRUMENT_COUNT, classic_song_length)
+    } else {
+        let (input, open_nbs_version) = field("nbs version", le_i8)(input)?;
+        let (input, vanilla_instrument_count) = field("vanilla instrument count", le_i8)(input)?;
+        let (input, song_length) = if open_nbs_version >= 3 { field("song length", le_i16)(input)? } else { (input, 0) }; //filled in from the noteblocks by song()
+        (input, open_nbs_version, vanilla_instrument_count, song_length)
+    };
+    let (layer_input, layer_count) = field("layer count", le_i16)(input)?;
+    if layer_count < 0 {
+        return Err(Failure(NbsError { input, expected: Expected::LayerCount(layer_count) }));
+    }
+    let input = layer_input;
+    let (input, name) = string(input, "song name")?;
+    let (input, author) = string(input, "song author")?;
+    let (input, orig_author) = string(input, "song original author")?;
+    let (input, description) = string(input, "song description")?;
+    let (input, tempo) = field("song tempo", le_i16)(input)?;
+    let (input, auto_save) = field("auto-saving", le_i8)(input)?;
+    let (input, auto_save_period) = field("auto-saving duration", le_i8)(input)?;
+    let (input, time_signature) = field("time signature", le_i8)(input)?;
+    let (input, minutes_spent) = field("minutes spent", le_i32)(input)?;
+    let (input, left_clicks) = field("left-clicks", le_i32)(input)?;
+    let (input, right_clicks) = field("right-clicks", le_i32)(input)?;
+    let (input, noteblocks_added) = field("note blocks added", le_i32)(input)?;
+    let (input, noteblocks_removed) = field("note blocks removed", le_i32)(input)?;
+    let (input, original_file_name) = string(input, "original file name")?;
+    let (input, looping, loop_count, loop_start_tick) = if open_nbs_version >= 4 {
+        let (input, looping) = field("loop on/off", le_i8)(input)?;
+        let (input, loop_count) = field("max loop count", le_i8)(input)?;
+        let (input, loop_start_tick) = field("loop start tick", le_i16)(input)?;
+        (input, looping, loop_count, loop_start_tick)
+    } else {
+        (input, 0, 0, 0)
+    };
 
     return Ok((
         input,
@@ -125,18 +154,28 @@ pub fn header(input: &[u8]) -> IResult<&[u8], Header> {
     ));
 }
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum NoteblockSection {
     SetTick(i32),
     SetLayer(i32),
     Noteblock(Noteblock),
 }
 
-pub fn noteblock(input: &[u8]) -> IResult<&[u8], Noteblock> {
-    let (input, instrument) = le_i8(input)?;
-    let (input, key) = le_i8(input)?;
-    let (input, volume) = le_i8(input)?;
-    let (input, panning) = le_u8(input)?;
-    let (input, pitch) = le_i16(input)?;
+pub fn noteblock(input: &[u8], version: i8) -> NbsResult<'_, Noteblock> {
+    let (input, instrument) = field("instrument", le_i8)(input)?;
+    let (input, key) = field("key", le_i8)(input)?;
+    if version < 4 {
+        return Ok((input,Noteblock {
+            instrument,
+            key,
+            volume: 100,
+            panning: 100,
+            pitch: 0
+        }))
+    }
+    let (input, volume) = field("velocity", le_i8)(input)?;
+    let (input, panning) = field("panning", le_u8)(input)?;
+    let (input, pitch) = field("pitch", le_i16)(input)?;
     return Ok((input,Noteblock {
         instrument,
         key,
@@ -145,12 +184,11 @@ pub fn noteblock(input: &[u8]) -> IResult<&[u8], Noteblock> {
         pitch
     }))
 }
-pub fn layer(input: &[u8]) -> IResult<&[u8], Layer> {
-    let (input, string_length) = le_i32(input)?;
-    let (input, name) = map_res(take(string_length as usize), std::str::from_utf8)(input)?;
-    let (input, locked) = le_i8(input)?; //1 is locked
-    let (input, volume) = le_i8(input)?; //0-100
-    let (input, stereo) = le_u8(input)?;
+pub fn layer(input: &[u8], version: i8) -> NbsResult<'_, Layer> {
+    let (input, name) = string(input, "layer name")?;
+    let (input, locked) = if version >= 4 { field("layer lock", le_i8)(input)? } else { (input, 0) }; //1 is locked
+    let (input, volume) = field("layer volume", le_i8)(input)?; //0-100
+    let (input, stereo) = if version >= 2 { field("layer stereo", le_u8)(input)? } else { (input, 100) };
     return Ok((input, Layer {
         name: name.into(),
         locked,
@@ -159,13 +197,11 @@ pub fn layer(input: &[u8]) -> IResult<&[u8], Layer> {
 
     }))
 }
-pub fn custom_instrument(input: &[u8]) -> IResult<&[u8], Instrument> {
-    let (input, string_length) = le_i32(input)?;
-    let (input, name) = map_res(take(string_length as usize), std::str::from_utf8)(input)?;
-    let (input, string_length) = le_i32(input)?;
-    let (input, sound_file) = map_res(take(string_length as usize), std::str::from_utf8)(input)?;
-    let (input, sound_key) = le_i8(input)?;
-    let (input, press_key) = le_i8(input)?;
+pub fn custom_instrument(input: &[u8]) -> NbsResult<'_, Instrument> {
+    let (input, name) = string(input, "instrument name")?;
+    let (input, sound_file) = string(input, "sound file")?;
+    let (input, sound_key) = field("sound pitch", le_i8)(input)?;
+    let (input, press_key) = field("press key", le_i8)(input)?;
     return Ok((input, Instrument {
         name: name.into(),
         sound_file: sound_file.into(),
@@ -174,62 +210,675 @@ pub fn custom_instrument(input: &[u8]) -> IResult<&[u8], Instrument> {
     }))
 }
 
-pub fn song(input: &[u8]) -> IResult<&[u8], Song> {
-    let (mut input, header) = header(input)?;
+/* everything decoded before the first error, the header is the only part that has to be intact */
+struct PartialSong<'a> {
+    song: Song,
+    error: Option<ParseError>,
+    dropped_noteblocks: usize,
+    declared_custom_instruments: usize,
+    highest_instrument: Option<(&'a [u8], i8)>, //checked once the custom instruments are known
+}
+
+fn partial_song(input: &[u8]) -> Result<PartialSong<'_>, ParseError> {
+    let (mut rest, mut header) = header(input).map_err(|error| ParseError::new(input, Section::Header, error))?;
+    let mut error: Option<ParseError> = None;
     //NOTEBLOCKS
     let mut noteblocks: Vec<NoteblockSection> = Vec::new();
     let mut tick: i32=-1;
     let mut layer_pos: i32=-1;
-    loop {
+    let mut complete_tick: i32=-1;
+    let mut complete_len: usize=0;
+    let mut highest_instrument: Option<(&[u8], i8)> = None;
+    let notes_end = loop {
         if layer_pos==-1 {
-            let tick_jump = le_i16(input)?; input = tick_jump.0;
-            if tick_jump.1!=0 {
-                // println!("tick_jump was {:?}",tick_jump.1);
-                tick+=i32::from(tick_jump.1);
+            let (next, tick_jump) = match jump(rest, "tick jump") {
+                Ok(out) => out,
+                Err(err) => break Err(err),
+            };
+            rest = next;
+            if tick_jump!=0 {
+                tick+=i32::from(tick_jump);
                 noteblocks.push(SetTick(tick));
-                // continue;
             }else{
-                break;
+                break Ok(());
             }
         }
-        let layer_jump = le_i16(input)?; input = layer_jump.0;
-
-        if layer_jump.1==0 {
+        let (next, layer_jump) = match jump(rest, "layer jump") {
+            Ok(out) => out,
+            Err(err) => break Err(err),
+        };
+        if layer_jump==0 {
+            rest = next;
             layer_pos = -1;
+            complete_tick = tick;
+            complete_len = noteblocks.len();
             continue;
         }
-        layer_pos+=i32::from(layer_jump.1);
+        layer_pos+=i32::from(layer_jump);
+        if layer_pos >= i32::from(header.layer_count) {
+            break Err(Failure(NbsError {
+                input: rest,
+                expected: Expected::Layer { index: layer_pos, layer_count: header.layer_count },
+            }));
+        }
+        rest = next;
         noteblocks.push(SetLayer(layer_pos));
 
-        let out: (&[u8], Noteblock) = noteblock(&input).unwrap();
-        input=out.0;
-        noteblocks.push(NoteblockSection::Noteblock(out.1));
+        let (next, block) = match noteblock(rest, header.open_nbs_version) {
+            Ok(out) => out,
+            Err(err) => break Err(err),
+        };
+        if block.instrument < 0 {
+            break Err(Failure(NbsError {
+                input: rest,
+                expected: Expected::Instrument { index: block.instrument, instrument_count: header.vanilla_instrument_count as i16 },
+            }));
+        }
+        if highest_instrument.is_none_or(|(_, highest)| block.instrument > highest) {
+            highest_instrument = Some((rest, block.instrument));
+        }
+        rest = next;
+        noteblocks.push(NoteblockSection::Noteblock(block));
+    };
+    let mut dropped_noteblocks = 0;
+    if let Err(err) = notes_end {
+        //only whole ticks are kept, the one being read when the stream broke goes
+        dropped_noteblocks = noteblocks[complete_len..].iter()
+            .filter(|section| matches!(section, NoteblockSection::SetLayer(_))) //also counts a note cut off halfway
+            .count();
+        noteblocks.truncate(complete_len);
+        tick = complete_tick;
+        error = Some(ParseError::new(input, Section::Noteblocks, err));
+        rest = &[]; //the layers can't be found without the end of the stream
+    }
+    if header.open_nbs_version == 1 || header.open_nbs_version == 2 {
+        header.song_length = tick.max(0) as i16; //not stored before version 3
     }
     let mut layers: Vec<Layer> = Vec::new();
     let mut custom_instruments: Vec<Instrument> = Vec::new();
+    let mut declared_custom_instruments = 0;
 
     //LAYERS (optional)
-    if !input.is_empty() {
+    if !rest.is_empty() {
         for _ in 0..header.layer_count {
-            let layer = layer(input)?; input = layer.0;
-            layers.push(layer.1);
+            match layer(rest, header.open_nbs_version) {
+                Ok((next, layer)) => {
+                    rest = next;
+                    layers.push(layer);
+                },
+                Err(err) => {
+                    error = Some(ParseError::new(input, Section::Layers, err));
+                    rest = &[];
+                    break;
+                },
+            }
         }
     
         //CUSTOM INSTRUMENTS (optional)
-        if !input.is_empty() {
-            let custom_instruments_length = le_u8(input)?; input = custom_instruments_length.0;
-            for _ in 0..custom_instruments_length.1 {
-                let custom_instrument = custom_instrument(input)?; input = custom_instrument.0;
-                custom_instruments.push(custom_instrument.1);
+        if !rest.is_empty() {
+            match field("custom instrument count", le_u8)(rest) {
+                Ok((next, custom_instruments_length)) => {
+                    rest = next;
+                    declared_custom_instruments = custom_instruments_length as usize;
+                },
+                Err(err) => error = Some(ParseError::new(input, Section::CustomInstruments, err)),
+            }
+            for _ in 0..declared_custom_instruments {
+                match custom_instrument(rest) {
+                    Ok((next, custom_instrument)) => {
+                        rest = next;
+                        custom_instruments.push(custom_instrument);
+                    },
+                    Err(err) => {
+                        error = Some(ParseError::new(input, Section::CustomInstruments, err));
+                        break;
+                    },
+                }
             }
         }
     }
-    return Ok((input, Song {
-        header,
-        noteblocks,
-        layers,
-        custom_instruments,
-    }))
+    Ok(PartialSong {
+        song: Song {
+            header,
+            noteblocks: Notes::from_sections(&noteblocks),
+            layers,
+            custom_instruments,
+        },
+        error,
+        dropped_noteblocks,
+        declared_custom_instruments,
+        highest_instrument,
+    })
+}
+
+/* the first note block using an instrument the song doesn't have */
+fn check_instruments(input: &[u8], partial: &PartialSong<'_>) -> Result<(), ParseError> {
+    let instrument_count = partial.song.instrument_count();
+    if let Some((position, highest)) = partial.highest_instrument {
+        if highest as i16 >= instrument_count {
+            return Err(ParseError::new(input, Section::Noteblocks, Failure(NbsError {
+                input: position,
+                expected: Expected::Instrument { index: highest, instrument_count },
+            })));
+        }
+    }
+    Ok(())
+}
+
+pub fn song(input: &[u8]) -> Result<Song, ParseError> {
+    let partial = partial_song(input)?;
+    if let Some(error) = partial.error {
+        return Err(error);
+    }
+    check_instruments(input, &partial)?;
+    Ok(partial.song)
+}
+
+/// Reads only the header, `input` can stop anywhere after it.
+///
+/// Files before version 3 don't store the song length, it's 0 here.
+pub fn song_header(input: &[u8]) -> Result<Header, ParseError> {
+    header(input).map(|(_, header)| header).map_err(|error| ParseError::new(input, Section::Header, error))
+}
+
+/// Checks a song that didn't come from [`song`] (deserialized or built by hand) the way
+/// parsing checks files, so it can be written and read back.
+pub fn validate(song: &Song) -> Result<(), Expected> {
+    let layer_count = song.header.layer_count;
+    if layer_count < 0 {
+        return Err(Expected::LayerCount(layer_count));
+    }
+    //no layers is fine too, playback and the writer fill in default ones
+    if song.layers.len() != layer_count as usize && !song.layers.is_empty() {
+        return Err(Expected::Layers { found: song.layers.len(), layer_count });
+    }
+    let instrument_count = song.instrument_count();
+    for (tick, layer, noteblock) in song.noteblocks.iter() {
+        if tick < 0 || layer < 0 {
+            return Err(Expected::Position { tick, layer });
+        }
+        if layer >= i32::from(layer_count) {
+            return Err(Expected::Layer { index: layer, layer_count });
+        }
+        if noteblock.instrument < 0 || noteblock.instrument as i16 >= instrument_count {
+            return Err(Expected::Instrument { index: noteblock.instrument, instrument_count });
+        }
+    }
+    Ok(())
+}
+
+/// What [`recover_song`] had to leave out of a damaged file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recovery {
+    /// The first problem in the file, everything after it is lost.
+    pub error: ParseError,
+    /// Note blocks in the tick that was being read when the stream broke.
+    pub dropped_noteblocks: usize,
+    /// Layers that couldn't be read and were replaced with default ones.
+    pub missing_layers: usize,
+    /// Custom instruments the file announced but that couldn't be read.
+    pub missing_custom_instruments: usize,
+    /// Note blocks removed because their instrument couldn't be read.
+    pub orphaned_noteblocks: usize,
+}
+
+impl fmt::Display for Recovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}; lost {} note blocks, {} layers and {} custom instruments",
+            self.error,
+            self.dropped_noteblocks + self.orphaned_noteblocks,
+            self.missing_layers,
+            self.missing_custom_instruments)
+    }
+}
+
+/// Lenient version of [`song`] for truncated or corrupted files.
+///
+/// Keeps the header, the note blocks up to the last complete tick and whatever
+/// layers and custom instruments could be read, and reports what was lost.
+/// Only fails when the header itself can't be read.
+pub fn recover_song(input: &[u8]) -> Result<(Song, Option<Recovery>), ParseError> {
+    let partial = partial_song(input)?;
+    let error = match (partial.error.clone(), check_instruments(input, &partial)) {
+        (Some(error), _) | (None, Err(error)) => error,
+        (None, Ok(())) => return Ok((partial.song, None)),
+    };
+    let mut song = partial.song;
+
+    //a file may end right after the note blocks, but not when it broke before the layers were done
+    let layers_cut = partial.error.as_ref().is_some_and(|error| matches!(error.section, Section::Noteblocks | Section::Layers));
+    let missing_layers = if layers_cut {
+        (song.header.layer_count as usize).saturating_sub(song.layers.len())
+    } else {
+        0
+    };
+    for _ in 0..missing_layers {
+        song.layers.push(Layer {
+            name: String::new(),
+            locked: 0,
+            volume: 100,
+            stereo: 100,
+        });
+    }
+
+    //note blocks need their instrument, so drop the ones whose instrument was lost
+    let instrument_count = song.instrument_count();
+    let orphaned_noteblocks = song.noteblocks.retain(|_, _, block| (block.instrument as i16) < instrument_count);
+
+    let missing_custom_instruments = partial.declared_custom_instruments.saturating_sub(song.custom_instruments.len());
+    Ok((song, Some(Recovery {
+        error,
+        dropped_noteblocks: partial.dropped_noteblocks,
+        missing_layers,
+        missing_custom_instruments,
+        orphaned_noteblocks,
+    })))
+}
+
+/// Part of the file a [`ParseError`] happened in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Section {
+    Header,
+    Noteblocks,
+    Layers,
+    CustomInstruments,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Section::Header => write!(f, "header"),
+            Section::Noteblocks => write!(f, "note block stream"),
+            Section::Layers => write!(f, "layers"),
+            Section::CustomInstruments => write!(f, "custom instruments"),
+        }
+    }
+}
+
+/// What the parser wanted to read when it gave up.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expected {
+    /// The file ended (or had too few bytes left) while reading this field.
+    Field(&'static str),
+    /// A string length that is negative or longer than the rest of the file.
+    StringLength { field: &'static str, length: i32 },
+    /// A string that isn't valid utf-8.
+    Utf8(&'static str),
+    /// A negative layer count.
+    LayerCount(i16),
+    /// A jump that would move backwards through the ticks or layers.
+    Jump { field: &'static str, jump: i16 },
+    /// A note block on a layer past the header's layer count.
+    Layer { index: i32, layer_count: i16 },
+    /// A note block using an instrument the song doesn't have.
+    Instrument { index: i8, instrument_count: i16 },
+    /// Layer entries that don't match the header's layer count.
+    Layers { found: usize, layer_count: i16 },
+    /// A note block before the first tick or layer.
+    Position { tick: i32, layer: i32 },
+    /// Anything nom reports on its own.
+    Nom(ErrorKind),
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expected::Field(field) => write!(f, "the {}, but the file ended", field),
+            Expected::StringLength { field, length } => write!(f, "a {} length that fits in the file, got {}", field, length),
+            Expected::Utf8(field) => write!(f, "a utf-8 {}", field),
+            Expected::LayerCount(count) => write!(f, "a non-negative layer count, got {}", count),
+            Expected::Jump { field, jump } => write!(f, "a non-negative {}, got {}", field, jump),
+            Expected::Layer { index, layer_count } => write!(f, "a layer below the layer count of {}, got {}", layer_count, index),
+            Expected::Instrument { index, instrument_count } => write!(f, "an instrument below {}, got {}", instrument_count, index),
+            Expected::Layers { found, layer_count } => write!(f, "{} layers like the layer count says, got {}", layer_count, found),
+            Expected::Position { tick, layer } => write!(f, "a note block at a non-negative tick and layer, got tick {} layer {}", tick, layer),
+            Expected::Nom(kind) => write!(f, "{}", kind.description()),
+        }
+    }
+}
+
+/// Error the sub-parsers return, pointing at the input where they stopped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NbsError<'a> {
+    pub input: &'a [u8],
+    pub expected: Expected,
+}
+
+impl<'a> nom::error::ParseError<&'a [u8]> for NbsError<'a> {
+    fn from_error_kind(input: &'a [u8], kind: ErrorKind) -> Self {
+        NbsError { input, expected: Expected::Nom(kind) }
+    }
+
+    fn append(_: &'a [u8], _: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+pub type NbsResult<'a, T> = IResult<&'a [u8], T, NbsError<'a>>;
+
+/// Why a song couldn't be read, with the byte offset from the start of the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub offset: usize,
+    pub section: Section,
+    pub expected: Expected,
+}
 
+impl ParseError {
+    fn new(file: &[u8], section: Section, error: nom::Err<NbsError<'_>>) -> ParseError {
+        match error {
+            Error(error) | Failure(error) => ParseError {
+                offset: file.len() - error.input.len(),
+                section,
+                expected: error.expected,
+            },
+            nom::Err::Incomplete(_) => ParseError { //only the streaming parsers report this
+                offset: file.len(),
+                section,
+                expected: Expected::Nom(ErrorKind::Eof),
+            },
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {} at byte {}: expected {}", self.section, self.offset, self.expected)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/* names whatever the parser was reading when it ran out of input */
+fn field<'a, O>(name: &'static str, mut parser: impl FnMut(&'a [u8]) -> NbsResult<'a, O>) -> impl FnMut(&'a [u8]) -> NbsResult<'a, O> {
+    move |input: &'a [u8]| parser(input).map_err(|error| error.map(|_| NbsError { input, expected: Expected::Field(name) }))
+}
+
+fn string<'a>(input: &'a [u8], name: &'static str) -> NbsResult<'a, &'a str> {
+    let (rest, length) = field(name, le_i32)(input)?;
+    if length < 0 || length as usize > rest.len() {
+        return Err(Failure(NbsError { input, expected: Expected::StringLength { field: name, length } }));
+    }
+    let (rest, bytes) = take(length as usize)(rest)?;
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Ok((rest, text)),
+        Err(_) => Err(Failure(NbsError { input, expected: Expected::Utf8(name) })),
+    }
+}
+
+fn jump<'a>(input: &'a [u8], name: &'static str) -> NbsResult<'a, i16> {
+    let (rest, jump) = field(name, le_i16)(input)?;
+    if jump < 0 {
+        return Err(Failure(NbsError { input, expected: Expected::Jump { field: name, jump } }));
+    }
+    Ok((rest, jump))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A version 5 song at 10 ticks per second with `layer_count` layers left to playback's defaults
+    /// and no note blocks, for tests anywhere in the crate to fill in.
+    pub(crate) fn empty_song(layer_count: i16) -> Song {
+        Song {
+            header: Header {
+                open_nbs_version: 5,
+                vanilla_instrument_count: 16,
+                song_length: 0,
+                layer_count,
+                name: String::new(),
+                author: String::new(),
+                orig_author: String::new(),
+                description: String::new(),
+                tempo: 1000,
+                auto_save: 0,
+                auto_save_period: 10,
+                time_signature: 4,
+                minutes_spent: 0,
+                left_clicks: 0,
+                right_clicks: 0,
+                noteblocks_added: 0,
+                noteblocks_removed: 0,
+                original_file_name: String::new(),
+                looping: 0,
+                loop_count: 0,
+                loop_start_tick: 0,
+            },
+            noteblocks: Notes::new(),
+            layers: Vec::new(),
+            custom_instruments: Vec::new(),
+        }
+    }
+
+    /* the fixtures are written out by hand field by field, not with the writer they'd have to check */
+    fn string_bytes(text: &str) -> Vec<u8> {
+        [&(text.len() as i32).to_le_bytes()[..], text.as_bytes()].concat()
+    }
+
+    /* the fields every version has between the layer count and the loop settings */
+    fn common_header_bytes() -> Vec<u8> {
+        [
+            string_bytes("Song"),
+            string_bytes("Me"),
+            string_bytes(""),
+            string_bytes("desc"),
+            vec![0xe8, 0x03], //tempo 1000
+            vec![1, 10, 3], //auto-saving, its period, time signature
+            vec![1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 5, 0, 0, 0], //minutes, clicks, blocks added and removed
+            string_bytes("a.mid"),
+        ].concat()
+    }
+
+    fn header_bytes(version: i8) -> Vec<u8> {
+        let start: Vec<u8> = match version {
+            0 => vec![4, 0], //song length, which also says the file is classic
+            1 | 2 => vec![0, 0, version as u8, 10],
+            _ => vec![0, 0, version as u8, 16, 4, 0],
+        };
+        let loop_settings: Vec<u8> = if version >= 4 { vec![1, 2, 1, 0] } else { Vec::new() };
+        [start, vec![2, 0], common_header_bytes(), loop_settings].concat()
+    }
+
+    /* tick 0 layer 0 and tick 4 layer 1 */
+    fn noteblock_bytes(version: i8, second_instrument: u8) -> Vec<u8> {
+        if version < 4 {
+            return vec![1, 0, 1, 0, 0, 45, 0, 0, 4, 0, 2, 0, second_instrument, 50, 0, 0, 0, 0];
+        }
+        vec![
+            1, 0, 1, 0, 0, 45, 100, 100, 0, 0, 0, 0,
+            4, 0, 2, 0, second_instrument, 50, 60, 20, 0x9c, 0xff, 0, 0,
+            0, 0,
+        ]
+    }
+
+    fn layer_bytes(version: i8) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (name, locked, volume, stereo) in [("Melody", 0, 100, 100), ("Bass", 1, 50, 0)] {
+            bytes.extend(string_bytes(name));
+            if version >= 4 {
+                bytes.push(locked);
+            }
+            bytes.push(volume);
+            if version >= 2 {
+                bytes.push(stereo);
+            }
+        }
+        bytes
+    }
+
+    fn custom_instrument_bytes() -> Vec<u8> {
+        [vec![1], string_bytes("Piano"), string_bytes("piano.ogg"), vec![45, 1]].concat()
+    }
+
+    fn file_bytes(version: i8) -> Vec<u8> {
+        let vanilla = if version < 3 { 10 } else { 16 };
+        [header_bytes(version), noteblock_bytes(version, vanilla), layer_bytes(version), custom_instrument_bytes()].concat()
+    }
+
+    /* what the fixture of each version holds, with the defaults for fields it doesn't store */
+    fn fixture_song(version: i8) -> Song {
+        let vanilla_instrument_count = if version < 3 { 10 } else { 16 };
+        let mut noteblocks = Notes::new();
+        noteblocks.insert(0, 0, Noteblock { instrument: 0, key: 45, volume: 100, panning: 100, pitch: 0 });
+        noteblocks.insert(4, 1, if version < 4 {
+            Noteblock { instrument: vanilla_instrument_count, key: 50, volume: 100, panning: 100, pitch: 0 }
+        } else {
+            Noteblock { instrument: vanilla_instrument_count, key: 50, volume: 60, panning: 20, pitch: -100 }
+        });
+        Song {
+            header: Header {
+                open_nbs_version: version,
+                vanilla_instrument_count,
+                song_length: 4,
+                layer_count: 2,
+                name: "Song".into(),
+                author: "Me".into(),
+                orig_author: String::new(),
+                description: "desc".into(),
+                tempo: 1000,
+                auto_save: 1,
+                auto_save_period: 10,
+                time_signature: 3,
+                minutes_spent: 1,
+                left_clicks: 2,
+                right_clicks: 3,
+                noteblocks_added: 4,
+                noteblocks_removed: 5,
+                original_file_name: "a.mid".into(),
+                looping: if version >= 4 { 1 } else { 0 },
+                loop_count: if version >= 4 { 2 } else { 0 },
+                loop_start_tick: if version >= 4 { 1 } else { 0 },
+            },
+            noteblocks,
+            layers: vec![
+                Layer { name: "Melody".into(), locked: 0, volume: 100, stereo: 100 },
+                Layer { name: "Bass".into(), locked: if version >= 4 { 1 } else { 0 }, volume: 50, stereo: if version >= 2 { 0 } else { 100 } },
+            ],
+            custom_instruments: vec![Instrument { name: "Piano".into(), sound_file: "piano.ogg".into(), sound_key: 45, press_key: 1 }],
+        }
+    }
+
+    #[test]
+    fn reads_every_version_layout() {
+        for version in 0..=5 {
+            let parsed = song(&file_bytes(version)).unwrap_or_else(|error| panic!("version {}: {}", version, error));
+            assert_eq!(parsed, fixture_song(version), "version {}", version);
+        }
+    }
+
+    #[test]
+    fn song_length_before_version_3_comes_from_the_notes() {
+        for version in [1, 2] {
+            let header = song_header(&file_bytes(version)).unwrap();
+            assert_eq!(header.song_length, 0, "version {}", version);
+            assert_eq!(song(&file_bytes(version)).unwrap().header.song_length, 4, "version {}", version);
+        }
+    }
+
+    #[test]
+    fn files_may_end_after_the_notes() {
+        let bytes = [header_bytes(5), noteblock_bytes(5, 0)].concat();
+        let parsed = song(&bytes).unwrap();
+        assert!(parsed.layers.is_empty());
+        assert!(parsed.custom_instruments.is_empty());
+        assert_eq!(parsed.noteblocks.len(), 2);
+    }
+
+    fn parse_error(bytes: &[u8]) -> ParseError {
+        song(bytes).expect_err("the file should be rejected")
+    }
+
+    #[test]
+    fn truncated_headers_name_the_field() {
+        let bytes = file_bytes(5);
+        assert_eq!(parse_error(&bytes[..5]), ParseError { offset: 4, section: Section::Header, expected: Expected::Field("song length") });
+        assert_eq!(parse_error(&bytes[..10]), ParseError { offset: 8, section: Section::Header, expected: Expected::Field("song name") });
+    }
+
+    #[test]
+    fn string_lengths_have_to_fit() {
+        for length in [-1, 1000] {
+            let mut bytes = file_bytes(5);
+            bytes[8..12].copy_from_slice(&i32::to_le_bytes(length)); //the song name
+            assert_eq!(parse_error(&bytes), ParseError { offset: 8, section: Section::Header, expected: Expected::StringLength { field: "song name", length } });
+        }
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn jumps_go_forward() {
+        let notes = header_bytes(5).len();
+        let bytes = [header_bytes(5), vec![0xff, 0xff]].concat();
+        assert_eq!(parse_error(&bytes), ParseError { offset: notes, section: Section::Noteblocks, expected: Expected::Jump { field: "tick jump", jump: -1 } });
+        let bytes = [header_bytes(5), vec![1, 0, 0xfe, 0xff]].concat();
+        assert_eq!(parse_error(&bytes), ParseError { offset: notes + 2, section: Section::Noteblocks, expected: Expected::Jump { field: "layer jump", jump: -2 } });
+    }
+
+    #[test]
+    fn layers_stay_below_the_layer_count() {
+        let notes = header_bytes(5).len();
+        let bytes = [header_bytes(5), vec![1, 0, 3, 0, 0, 45, 100, 100, 0, 0, 0, 0, 0, 0]].concat();
+        assert_eq!(parse_error(&bytes), ParseError { offset: notes + 2, section: Section::Noteblocks, expected: Expected::Layer { index: 2, layer_count: 2 } });
+    }
+
+    #[test]
+    fn instruments_have_to_exist() {
+        let second_note = header_bytes(5).len() + 16;
+        //16 vanilla instruments and the one custom instrument
+        let bytes = [header_bytes(5), noteblock_bytes(5, 17), layer_bytes(5), custom_instrument_bytes()].concat();
+        assert_eq!(parse_error(&bytes), ParseError { offset: second_note, section: Section::Noteblocks, expected: Expected::Instrument { index: 17, instrument_count: 17 } });
+        let bytes = [header_bytes(5), noteblock_bytes(5, 0xff), layer_bytes(5), custom_instrument_bytes()].concat();
+        assert_eq!(parse_error(&bytes), ParseError { offset: second_note, section: Section::Noteblocks, expected: Expected::Instrument { index: -1, instrument_count: 16 } });
+    }
+
+    /* what the file still holds and what recovery says it lost, cut at `length` bytes */
+    fn recover_cut(length: usize) -> (Song, Recovery) {
+        let (song, recovery) = recover_song(&file_bytes(5)[..length]).unwrap();
+        (song, recovery.expect("a cut file needs recovering"))
+    }
+
+    #[test]
+    fn recovery_needs_the_header() {
+        assert_eq!(recover_song(&file_bytes(5)[..10]), Err(ParseError { offset: 8, section: Section::Header, expected: Expected::Field("song name") }));
+    }
+
+    #[test]
+    fn recovery_keeps_the_ticks_before_a_cut_in_the_notes() {
+        let notes = header_bytes(5).len();
+        let (song, recovery) = recover_cut(notes + 18); //at the velocity of the note on tick 4
+        assert_eq!(recovery.error.section, Section::Noteblocks);
+        assert_eq!(recovery.error.offset, notes + 18);
+        assert_eq!((recovery.dropped_noteblocks, recovery.missing_layers, recovery.missing_custom_instruments, recovery.orphaned_noteblocks), (1, 2, 0, 0));
+        assert_eq!(song.noteblocks.iter().map(|(tick, layer, _)| (tick, layer)).collect::<Vec<_>>(), vec![(0, 0)]);
+        assert_eq!(song.layers.len(), 2);
+    }
+
+    #[test]
+    fn recovery_fills_in_layers_after_a_cut_in_the_layers() {
+        let layers = header_bytes(5).len() + noteblock_bytes(5, 16).len();
+        let (song, recovery) = recover_cut(layers + 14); //inside the second layer's name
+        assert_eq!(recovery.error.section, Section::Layers);
+        assert_eq!(recovery.error.offset, layers + 13);
+        //the custom instrument went with the rest of the file, and the note using it
+        assert_eq!((recovery.dropped_noteblocks, recovery.missing_layers, recovery.missing_custom_instruments, recovery.orphaned_noteblocks), (0, 1, 0, 1));
+        assert_eq!(song.layers[0], fixture_song(5).layers[0]);
+        assert_eq!(song.layers.len(), 2);
+        assert_eq!(song.noteblocks.len(), 1);
+    }
+
+    #[test]
+    fn recovery_drops_notes_of_instruments_cut_off() {
+        let instruments = header_bytes(5).len() + noteblock_bytes(5, 16).len() + layer_bytes(5).len();
+        let (song, recovery) = recover_cut(instruments + 5); //inside the custom instrument's name
+        assert_eq!(recovery.error.section, Section::CustomInstruments);
+        assert_eq!(recovery.error.offset, instruments + 1);
+        assert_eq!((recovery.dropped_noteblocks, recovery.missing_layers, recovery.missing_custom_instruments, recovery.orphaned_noteblocks), (0, 0, 1, 1));
+        assert_eq!(song.layers, fixture_song(5).layers);
+        assert!(song.custom_instruments.is_empty());
+    }
+
+    #[test]
+    fn intact_files_need_no_recovery() {
+        assert_eq!(recover_song(&file_bytes(5)), Ok((fixture_song(5), None)));
+    }
+}
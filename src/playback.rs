@@ -0,0 +1,1044 @@
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "audio")]
+use rodio::{OutputStream, Source};
+
+use crate::parsers::{Instrument, Layer, Noteblock, Song};
+use crate::playlist::Transition;
+use crate::render::{load_sounds, silent_warning, Sound};
+
+pub const DEFAULT_INSTRUMENTS: [&str; 16] = ["harp","dbass","bdrum","sdrum","click","guitar","flute","bell","icechime","xylobone","iron_xylophone","cow_bell","didgeridoo","bit","banjo","pling"];
+
+/// Name of the custom instrument Note Block Studio uses to change the tempo mid-song.
+pub const TEMPO_CHANGER: &str = "Tempo Changer";
+
+/* far below a tick at any tempo, far above the rounding of summed tick lengths */
+const SEEK_EPSILON: f64 = 1e-6;
+
+/// The vanilla instruments the song uses followed by its custom ones, indexed like [`Noteblock::instrument`].
+pub fn instruments(song: &Song) -> Vec<Instrument> {
+    let mut instruments: Vec<Instrument> = DEFAULT_INSTRUMENTS.iter()
+        .take(song.header.vanilla_instrument_count.max(0) as usize)
+        .map(|name| Instrument {
+            name: (*name).into(),
+            sound_file: format!("{}.ogg", name),
+            sound_key: 45,
+            press_key: 1
+        })
+        .collect();
+    instruments.extend(song.custom_instruments.iter().cloned());
+    instruments
+}
+
+/// Index of the tempo changer instrument, -1 when the song doesn't have one.
+pub fn tempo_changer_index(instruments: &[Instrument]) -> i8 {
+    instruments.iter()
+        .position(|instrument| instrument.name == TEMPO_CHANGER)
+        .map_or(-1, |index| index as i8)
+}
+
+/// The song's layers, or default ones when the file didn't store any.
+pub fn layers(song: &Song) -> Vec<Layer> {
+    if !song.layers.is_empty() {
+        return song.layers.clone();
+    }
+    (0..song.header.layer_count).map(|i| Layer {
+        name: format!("default_layer_{}",i),
+        locked: 0,
+        volume: 100,
+        stereo: 100
+    }).collect()
+}
+
+/// Playback rate of the instrument's sample for the note's key and fine pitch.
+pub fn note_speed(noteblock: &Noteblock, instrument: &Instrument) -> f32 {
+    (2_f64.powf(
+        (
+            (
+                (noteblock.key as f64)-(instrument.sound_key as f64)
+            )+(
+                (noteblock.pitch as f64)/100_f64
+            )
+        )*(1_f64/12_f64))) as f32
+}
+
+/// Note volume scaled by its layer's volume, 1.0 is full volume.
+pub fn note_volume(noteblock: &Noteblock, layer: &Layer) -> f32 {
+    (noteblock.volume as f32 * layer.volume as f32)/10000_f32
+}
+
+/// Where the note sits between the speakers, -1.0 is left and 1.0 right.
+///
+/// Like Note Block Studio, a centred layer leaves the note's own panning alone,
+/// otherwise the two are averaged.
+pub fn note_panning(noteblock: &Noteblock, layer: &Layer) -> f32 {
+    let note = (noteblock.panning.min(200) as f32 - 100_f32)/100_f32;
+    if layer.stereo == 100 {
+        return note;
+    }
+    let layer = (layer.stereo.min(200) as f32 - 100_f32)/100_f32;
+    (note+layer)/2_f32
+}
+
+/// Left and right gains for a panning, constant power so a note sounds as loud
+/// wherever it is. The centre keeps both channels at full volume, a hard pan is 3 dB louder on its side.
+pub fn pan_gains(panning: f32) -> (f32, f32) {
+    let angle = (panning.clamp(-1_f32, 1_f32)+1_f32)*std::f32::consts::FRAC_PI_4;
+    (angle.cos()*std::f32::consts::SQRT_2, angle.sin()*std::f32::consts::SQRT_2)
+}
+
+/// The tick a song that ends at `tick` loops back from, the end of its bar of 16 ticks.
+pub fn loop_end_tick(tick: i32) -> i32{
+    (((tick+1) as f64/16_f64).ceil()*16_f64) as i32
+}
+
+/// How many times the header says the song jumps back to its loop start, None is forever.
+pub fn header_loops(song: &Song) -> Option<u32> {
+    if song.header.looping == 0 {
+        Some(0)
+    } else if song.header.loop_count <= 0 {
+        None
+    } else {
+        Some(song.header.loop_count as u32)
+    }
+}
+
+/// A note ready to be played: which sample, how fast and how loud.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduledNote {
+    pub instrument: usize,
+    pub layer: i32,
+    pub speed: f32,
+    pub volume: f32,
+    /// -1.0 is left, 1.0 is right, see [`note_panning`].
+    pub panning: f32,
+}
+
+/// The notes of one tick and when they start, in seconds from the start of playback.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduledTick {
+    pub tick: i32,
+    pub time: f64,
+    pub notes: Vec<ScheduledNote>,
+    /// Ticks per second from this tick on, after its tempo changers.
+    pub tempo: f64,
+    /// How many times the schedule had jumped back to the loop start before this tick.
+    pub pass: u32,
+}
+
+/* borrowed for one walk through a song, shared while playing so the mixer's schedule is cheap to copy */
+#[derive(Clone)]
+enum SongRef<'a> {
+    Borrowed(&'a Song),
+    Shared(Arc<Song>),
+}
+
+impl Deref for SongRef<'_> {
+    type Target = Song;
+
+    fn deref(&self) -> &Song {
+        match self {
+            SongRef::Borrowed(song) => song,
+            SongRef::Shared(song) => song,
+        }
+    }
+}
+
+impl SongRef<'_> {
+    /* copies the song first when someone else holds it too */
+    fn to_mut(&mut self) -> &mut Song {
+        if let SongRef::Borrowed(song) = *self {
+            *self = SongRef::Shared(Arc::new(song.clone()));
+        }
+        match self {
+            SongRef::Shared(song) => Arc::make_mut(song),
+            SongRef::Borrowed(_) => unreachable!(),
+        }
+    }
+
+    /* not edited since one was copied from the other */
+    fn same(&self, other: &SongRef<'_>) -> bool {
+        std::ptr::eq::<Song>(&**self, &**other)
+    }
+}
+
+/// Walks a song tick by tick the way it should be heard, applying tempo
+/// changers and jumping back to the loop start.
+#[derive(Clone)]
+pub struct Schedule<'a> {
+    song: SongRef<'a>,
+    instruments: Vec<Instrument>,
+    layers: Vec<Layer>,
+    tempo_changer_index: i8,
+    loops: Option<u32>,
+    loops_left: Option<u32>,
+    /// How many times it jumped back to the loop start so far.
+    passes: u32,
+    next_tick: Option<i32>,
+    last_tick: i32,
+    time: f64,
+    tick_length: f64,
+}
+
+impl<'a> Schedule<'a> {
+    /// Loops the way the song's header asks for.
+    pub fn new(song: &'a Song) -> Self {
+        Self::with_loops(song, header_loops(song))
+    }
+
+    /// `loops` is how many times a looping song jumps back to its loop start, None loops forever.
+    /// Songs that don't loop ignore it.
+    pub fn with_loops(song: &'a Song, loops: Option<u32>) -> Self {
+        Self::from_song(SongRef::Borrowed(song), loops)
+    }
+
+    fn from_song(song: SongRef<'a>, loops: Option<u32>) -> Self {
+        let instruments = instruments(&song);
+        let tempo_changer_index = tempo_changer_index(&instruments);
+        Schedule {
+            instruments,
+            layers: layers(&song),
+            tempo_changer_index,
+            loops,
+            loops_left: if song.header.looping == 0 { Some(0) } else { loops },
+            passes: 0,
+            next_tick: song.noteblocks.first_tick(),
+            last_tick: 0,
+            time: 0_f64,
+            tick_length: Self::header_tick_length(&song),
+            song,
+        }
+    }
+
+    /// The song whose notes are scheduled.
+    pub fn song(&self) -> &Song {
+        &self.song
+    }
+
+    /// Edits to the notes show up in the ticks not taken yet.
+    pub fn song_mut(&mut self) -> &mut Song {
+        self.song.to_mut()
+    }
+
+    /// Seconds from the start of the schedule until the song stops, None when it loops forever.
+    pub fn length(&self) -> Option<f64> {
+        self.loops_left?;
+        let mut rest = self.clone();
+        rest.by_ref().for_each(drop);
+        Some(rest.end_time())
+    }
+
+    /// The same song and loops from the start again.
+    pub fn rewound(&self) -> Self {
+        Self::from_song(self.song.clone(), self.loops)
+    }
+
+    /// Takes every tick before `tick` on this pass through the song, applying its tempo
+    /// changers, and returns the time `tick` falls on. Past the end that's where the song ends or loops.
+    pub fn seek(&mut self, tick: i32) -> f64 {
+        let passes = self.passes;
+        while self.next_tick.is_some_and(|next| next < tick) && self.passes == passes {
+            self.next();
+        }
+        if self.passes != passes || self.next_tick.is_none() {
+            return self.time;
+        }
+        self.time + (tick-self.last_tick).max(0) as f64 * self.tick_length
+    }
+
+    /// Like [`Schedule::seek`] for a time, returns the tick it falls in and
+    /// `i32::MAX` past the end of this pass.
+    pub fn seek_time(&mut self, seconds: f64) -> i32 {
+        let passes = self.passes;
+        //times add up tick lengths like 0.1 that floats can't hold, a time right on a tick belongs to it
+        let seconds = seconds + SEEK_EPSILON;
+        while let Some(next) = self.next_tick {
+            if self.passes != passes || self.time + (next-self.last_tick) as f64 * self.tick_length > seconds {
+                break;
+            }
+            self.next();
+        }
+        if self.passes != passes || self.next_tick.is_none() {
+            return i32::MAX;
+        }
+        self.last_tick + ((seconds-self.time)/self.tick_length).max(0_f64) as i32
+    }
+
+    /// Ticks per second at this point of the schedule.
+    pub fn tempo(&self) -> f64 {
+        1_f64/self.tick_length
+    }
+
+    /// How many times the schedule jumped back to the loop start so far.
+    pub fn passes(&self) -> u32 {
+        self.passes
+    }
+
+    fn header_tick_length(song: &Song) -> f64 {
+        100_f64/(song.header.tempo.max(1) as f64)
+    }
+
+    /// Where the song stops, the end of the bar after its last tick, once every tick has been taken.
+    pub fn end_time(&self) -> f64 {
+        self.time
+    }
+}
+
+impl Schedule<'static> {
+    /// A schedule that keeps the song, for playing songs that outlive whoever loaded them.
+    pub fn owned(song: Song, loops: Option<u32>) -> Self {
+        Self::from_song(SongRef::Shared(Arc::new(song)), loops)
+    }
+}
+
+impl Iterator for Schedule<'_> {
+    type Item = ScheduledTick;
+
+    fn next(&mut self) -> Option<ScheduledTick> {
+        let tick = self.next_tick?;
+        let pass = self.passes;
+        self.time += (tick-self.last_tick) as f64 * self.tick_length;
+        self.last_tick = tick;
+        let time = self.time;
+
+        let mut notes: Vec<ScheduledNote> = Vec::new();
+        for (layer, noteblock) in self.song.noteblocks.tick(tick).into_iter().flatten() {
+            if noteblock.instrument == self.tempo_changer_index {
+                if noteblock.pitch > 0 {
+                    self.tick_length = 15_f64/(noteblock.pitch as f64); //the pitch holds the new tempo times 15
+                }
+                continue;
+            }
+            let (Some(instrument), Some(layer_entry)) = (self.instruments.get(noteblock.instrument as usize), self.layers.get(*layer as usize)) else {
+                continue;
+            };
+            notes.push(ScheduledNote {
+                instrument: noteblock.instrument as usize,
+                layer: *layer,
+                speed: note_speed(noteblock, instrument),
+                volume: note_volume(noteblock, layer_entry),
+                panning: note_panning(noteblock, layer_entry),
+            });
+        }
+
+        let tempo = 1_f64/self.tick_length;
+        self.next_tick = self.song.noteblocks.next_tick(tick);
+        if self.next_tick.is_none() {
+            //loops at the end of the bar
+            let end = loop_end_tick(tick);
+            self.time += (end-tick) as f64 * self.tick_length;
+            self.last_tick = end;
+            if self.loops_left != Some(0) {
+                self.loops_left = self.loops_left.map(|loops| loops - 1);
+                self.passes += 1;
+                let loop_start = self.song.header.loop_start_tick as i32;
+                self.last_tick = loop_start;
+                self.tick_length = Self::header_tick_length(&self.song);
+                self.next_tick = self.song.noteblocks.ticks(loop_start..).next().map(|(tick, _)| tick);
+            }
+        }
+        Some(ScheduledTick { tick, time, notes, tempo, pass })
+    }
+}
+
+/// Sample rate [`Player`] mixes at, the output converts it to the device's.
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// Decides which notes of a [`Track`] play.
+pub type NoteFilter = Box<dyn FnMut(&ScheduledNote) -> bool + Send>;
+
+/// A song ready for the [`Mixer`]: its schedule and the samples of its instruments.
+pub struct Track {
+    schedule: Schedule<'static>,
+    sounds: Arc<Vec<Sound>>,
+    /// Seconds until the song stops, None when it loops forever.
+    length: Option<f64>,
+    filter: Option<NoteFilter>,
+    /// Why the track plays silently, see [`Player::load`].
+    pub warning: Option<String>,
+}
+
+impl Track {
+    /// Loops the way the song's header asks for. `sounds` is indexed like [`instruments`],
+    /// notes of instruments without a sound are left out.
+    pub fn new(song: Song, sounds: Vec<Sound>) -> Track {
+        let loops = header_loops(&song);
+        Track::with_schedule(Schedule::owned(song, loops), sounds)
+    }
+
+    pub fn with_schedule(schedule: Schedule<'static>, sounds: Vec<Sound>) -> Track {
+        Track {
+            length: schedule.length(),
+            schedule,
+            sounds: Arc::new(sounds),
+            filter: None,
+            warning: None,
+        }
+    }
+
+    /// Only plays the notes the filter lets through.
+    pub fn with_filter(mut self, filter: impl FnMut(&ScheduledNote) -> bool + Send + 'static) -> Track {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    pub fn song(&self) -> &Song {
+        self.schedule.song()
+    }
+}
+
+/// What happened in the [`Mixer`] since it was last asked, by the ids songs were given.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MixerEvent {
+    /// The queued song took over.
+    Started(usize),
+    /// The song played to its end with nothing queued after it.
+    Finished(usize),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlayState {
+    #[default]
+    Stopped,
+    Playing,
+    Paused,
+}
+
+/// Where playback is, see [`Player::status`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Status {
+    pub state: PlayState,
+    /// Id of the current song.
+    pub id: Option<usize>,
+    /// The last tick whose notes started.
+    pub tick: Option<i32>,
+    /// Where playback is between ticks, what the editor's playhead shows.
+    pub position: f64,
+    /// Seconds into the current song, counting every loop.
+    pub seconds: f64,
+    /// Ticks per second, tempo changers included.
+    pub tempo: f64,
+    /// How many times the current song jumped back to its loop start.
+    pub loops: u32,
+}
+
+/* where a seek lands, worked out on a copy of the schedule */
+struct Seek {
+    id: usize,
+    schedule: Schedule<'static>,
+    tick: i32,
+    time: f64,
+}
+
+impl Seek {
+    fn to_tick(id: usize, schedule: &Schedule<'static>, tick: i32) -> Seek {
+        let end_tick = schedule.song().noteblocks.last_tick().map_or(0, loop_end_tick);
+        let mut schedule = schedule.rewound();
+        let time = schedule.seek(tick);
+        Seek { id, schedule, tick: tick.min(end_tick), time }
+    }
+
+    fn to_time(id: usize, schedule: &Schedule<'static>, seconds: f64) -> Seek {
+        Seek::to_tick(id, schedule, schedule.rewound().seek_time(seconds))
+    }
+}
+
+/* a song being mixed, two of them overlap while crossfading */
+struct Deck {
+    id: usize,
+    track: Track,
+    next: Option<ScheduledTick>,
+    tick: Option<i32>,
+    /// Schedule time of `tick`, and which pass through the song it was on.
+    tick_time: f64,
+    pass: u32,
+    tempo: f64,
+    /// Frame the schedule's time 0 falls on.
+    start: i64,
+    end: Option<i64>,
+    fade_in: Option<(i64, i64)>, //from frame, for frames
+    fade_out: Option<(i64, i64)>,
+}
+
+impl Deck {
+    fn new(id: usize, mut track: Track, start: i64, fade: i64, sample_rate: u32) -> Deck {
+        let mut deck = Deck {
+            id,
+            tempo: track.schedule.tempo(),
+            next: track.schedule.next(),
+            tick: None,
+            tick_time: 0_f64,
+            pass: 0,
+            track,
+            start,
+            end: None,
+            fade_in: if fade > 0 { Some((start, fade)) } else { None },
+            fade_out: None,
+        };
+        deck.update_end(sample_rate);
+        deck
+    }
+
+    fn update_end(&mut self, sample_rate: u32) {
+        self.end = self.track.length.map(|length| self.start + seconds_to_frames(length, sample_rate));
+    }
+
+    fn next_frame(&self, sample_rate: u32) -> Option<i64> {
+        self.next.as_ref().map(|tick| self.start + seconds_to_frames(tick.time, sample_rate))
+    }
+
+    /* the fractional tick `seconds` into the schedule falls on, its pass and the tempo there. the time
+    between the last tick and the next is split at the end of the bar when the song loops back there */
+    fn position(&self, seconds: f64) -> (f64, u32, f64) {
+        let from = self.tick.unwrap_or(0);
+        let ahead = from as f64 + (seconds-self.tick_time).max(0_f64) * self.tempo;
+        match &self.next {
+            Some(next) if next.pass == self.pass => (ahead.min(next.tick as f64), self.pass, self.tempo),
+            next => {
+                let song = self.track.song();
+                let end = song.noteblocks.last_tick().map_or(0, loop_end_tick).max(from) as f64;
+                match next {
+                    Some(next) if ahead >= end => {
+                        //the tempo goes back to the header's at the loop start
+                        let tempo = 1_f64/Schedule::header_tick_length(song);
+                        let back = (next.time-seconds).max(0_f64) * tempo;
+                        ((next.tick as f64 - back).max(song.header.loop_start_tick as f64), next.pass, tempo)
+                    },
+                    _ => (ahead.min(end), self.pass, self.tempo),
+                }
+            },
+        }
+    }
+
+    fn gain(&self, frame: i64) -> f32 {
+        let progress = |(from, length): (i64, i64)| {
+            if length <= 0 {
+                return if frame >= from { 1_f32 } else { 0_f32 };
+            }
+            ((frame-from).max(0) as f32 / length as f32).min(1_f32)
+        };
+        self.fade_in.map_or(1_f32, progress) * self.fade_out.map_or(1_f32, |fade| 1_f32 - progress(fade))
+    }
+
+    fn faded(&self, frame: i64) -> bool {
+        self.fade_out.is_some_and(|(from, length)| from + length <= frame)
+    }
+
+    fn finished(&self, frame: i64) -> bool {
+        (self.next.is_none() && self.end.is_none_or(|end| end <= frame)) || self.faded(frame)
+    }
+}
+
+fn seconds_to_frames(seconds: f64, sample_rate: u32) -> i64 {
+    (seconds.max(0_f64) * sample_rate as f64).round() as i64
+}
+
+/* a note ringing, it keeps its deck's samples alive */
+struct Voice {
+    deck: usize,
+    sounds: Arc<Vec<Sound>>,
+    instrument: usize,
+    position: f64,
+    step: f64,
+    gains: [f32; 2],
+}
+
+/// Mixes songs frame by frame, starting every note on the exact frame its tick
+/// falls on. [`Player`] runs one in real time, offline rendering runs one as fast as it can.
+pub struct Mixer {
+    sample_rate: u32,
+    frame: i64,
+    paused: bool,
+    /// The last one is the current song, the others fade out.
+    decks: Vec<Deck>,
+    queued: Option<(usize, Track)>,
+    transition: Transition,
+    voices: Vec<Voice>,
+    events: Vec<MixerEvent>,
+}
+
+impl Mixer {
+    pub fn new(sample_rate: u32) -> Mixer {
+        Mixer {
+            sample_rate,
+            frame: 0,
+            paused: false,
+            decks: Vec::new(),
+            queued: None,
+            transition: Transition::default(),
+            voices: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Cuts whatever is playing and starts the track now.
+    pub fn play(&mut self, id: usize, track: Track) {
+        self.stop();
+        self.decks.push(Deck::new(id, track, self.frame, 0, self.sample_rate));
+    }
+
+    /// Cuts everything, ringing notes too, and forgets the queued track.
+    pub fn stop(&mut self) {
+        self.decks.clear();
+        self.voices.clear();
+        self.queued = None;
+        self.paused = false;
+    }
+
+    /// Holds everything where it is, ringing notes too, until [`Mixer::resume`].
+    pub fn pause(&mut self) {
+        self.paused = !self.decks.is_empty();
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    /// Moves the current song to `tick`, on the first pass through it. Ringing notes are cut
+    /// and the tempo is whatever the tempo changers before `tick` set.
+    pub fn seek(&mut self, tick: i32) {
+        if let Some((id, schedule)) = self.current_schedule() {
+            self.finish_seek(Seek::to_tick(id, &schedule, tick));
+        }
+    }
+
+    /// Moves the current song to a time on its first pass, see [`Mixer::seek`].
+    pub fn seek_time(&mut self, seconds: f64) {
+        if let Some((id, schedule)) = self.current_schedule() {
+            self.finish_seek(Seek::to_time(id, &schedule, seconds));
+        }
+    }
+
+    /* a copy to work on without holding up the output, it shares the song */
+    fn current_schedule(&self) -> Option<(usize, Schedule<'static>)> {
+        self.decks.last().map(|current| (current.id, current.track.schedule.clone()))
+    }
+
+    /* false when another song took over or the song was edited while the seek was worked out */
+    fn finish_seek(&mut self, seek: Seek) -> bool {
+        let (frame, sample_rate) = (self.frame, self.sample_rate);
+        if !self.decks.last().is_some_and(|current| current.id == seek.id && current.track.schedule.song.same(&seek.schedule.song)) {
+            return false;
+        }
+        let Some(mut track) = self.decks.pop().map(|current| current.track) else {
+            return false;
+        };
+        track.schedule = seek.schedule;
+        let mut deck = Deck::new(seek.id, track, frame - seconds_to_frames(seek.time, sample_rate), 0, sample_rate);
+        deck.tick = Some(seek.tick);
+        deck.tick_time = seek.time;
+        self.decks.clear();
+        self.decks.push(deck);
+        self.voices.clear();
+        true
+    }
+
+    /// Plays the track once the current one ends, or replaces the one queued before.
+    pub fn queue(&mut self, track: Option<(usize, Track)>) {
+        self.queued = track;
+    }
+
+    /// Hands over to the queued track now.
+    pub fn skip(&mut self) {
+        let frame = self.frame;
+        if let Some(current) = self.decks.last_mut() {
+            current.fade_out = Some((frame, 0));
+        }
+        self.start_queued(frame);
+    }
+
+    pub fn set_transition(&mut self, transition: Transition) {
+        self.transition = transition;
+    }
+
+    /// Changes the current song, edits show up in the ticks that haven't played yet.
+    pub fn edit_song(&mut self, edit: impl FnOnce(&mut Song)) {
+        if let Some((id, schedule)) = self.edit_current(edit) {
+            self.set_length(id, &schedule, schedule.length());
+        }
+    }
+
+    /* returns a copy of the edited schedule to work out the new length on */
+    fn edit_current(&mut self, edit: impl FnOnce(&mut Song)) -> Option<(usize, Schedule<'static>)> {
+        let current = self.decks.last_mut()?;
+        edit(current.track.schedule.song_mut());
+        Some((current.id, current.track.schedule.clone()))
+    }
+
+    /* left alone when the song changed again since */
+    fn set_length(&mut self, id: usize, schedule: &Schedule<'static>, length: Option<f64>) {
+        let sample_rate = self.sample_rate;
+        if let Some(current) = self.decks.last_mut().filter(|current| current.id == id && current.track.schedule.song.same(&schedule.song)) {
+            current.track.length = length;
+            current.update_end(sample_rate);
+        }
+    }
+
+    /// Nothing is playing and no note rings any more.
+    pub fn silent(&self) -> bool {
+        self.decks.is_empty() && self.voices.is_empty()
+    }
+
+    pub fn status(&self) -> Status {
+        let Some(current) = self.decks.last() else {
+            return Status::default();
+        };
+        let seconds = (self.frame-current.start).max(0) as f64 / self.sample_rate as f64;
+        let (position, loops, tempo) = current.position(seconds);
+        Status {
+            state: if self.paused { PlayState::Paused } else { PlayState::Playing },
+            id: Some(current.id),
+            tick: current.tick,
+            position,
+            seconds,
+            tempo,
+            loops,
+        }
+    }
+
+    /// Events since the last call.
+    pub fn take_events(&mut self) -> Vec<MixerEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn fade_frames(&self) -> i64 {
+        match self.transition {
+            Transition::Gapless => 0,
+            Transition::Crossfade(seconds) => seconds_to_frames(seconds, self.sample_rate),
+        }
+    }
+
+    /* a looping song never hands over on its own */
+    fn handover_frame(&self) -> Option<i64> {
+        self.queued.as_ref()?;
+        let current = self.decks.last()?;
+        Some((current.end? - self.fade_frames()).max(current.start))
+    }
+
+    fn start_queued(&mut self, frame: i64) {
+        let Some((id, track)) = self.queued.take() else {
+            return;
+        };
+        let fade = self.fade_frames();
+        if let Some(current) = self.decks.last_mut().filter(|_| fade > 0) {
+            current.fade_out = Some((frame, fade));
+        }
+        self.decks.push(Deck::new(id, track, frame, fade, self.sample_rate));
+        self.events.push(MixerEvent::Started(id));
+    }
+
+    /* starts the notes that fall on this frame and retires finished songs */
+    fn start_frame(&mut self) {
+        let (frame, sample_rate) = (self.frame, self.sample_rate);
+        if self.handover_frame().is_some_and(|handover| handover <= frame) {
+            self.start_queued(frame);
+        }
+        for deck in &mut self.decks {
+            while deck.next_frame(sample_rate).is_some_and(|start| start <= frame) {
+                let tick = deck.next.take().unwrap();
+                for note in &tick.notes {
+                    if deck.track.filter.as_mut().is_some_and(|filter| !filter(note)) {
+                        continue;
+                    }
+                    let Some(sound) = deck.track.sounds.get(note.instrument).filter(|sound| sound.frames() > 1) else {
+                        continue;
+                    };
+                    let (left, right) = pan_gains(note.panning);
+                    self.voices.push(Voice {
+                        deck: deck.id,
+                        sounds: deck.track.sounds.clone(),
+                        instrument: note.instrument,
+                        position: 0_f64,
+                        step: note.speed as f64 * sound.sample_rate as f64 / sample_rate as f64,
+                        gains: [left*note.volume, right*note.volume],
+                    });
+                }
+                deck.tick = Some(tick.tick);
+                deck.tick_time = tick.time;
+                deck.pass = tick.pass;
+                deck.tempo = tick.tempo;
+                deck.next = deck.track.schedule.next();
+            }
+        }
+        if self.queued.is_none() {
+            if let Some(current) = self.decks.last().filter(|current| current.finished(frame)) {
+                self.events.push(MixerEvent::Finished(current.id));
+            }
+        }
+        if self.decks.iter().any(|deck| deck.finished(frame)) {
+            let faded: Vec<usize> = self.decks.iter().filter(|deck| deck.faded(frame)).map(|deck| deck.id).collect();
+            self.voices.retain(|voice| !faded.contains(&voice.deck));
+            self.decks.retain(|deck| !deck.finished(frame));
+        }
+    }
+
+    /// Fills interleaved stereo samples, moving time forward by half their count in frames.
+    /// Paused it's silence and time stands still.
+    pub fn mix(&mut self, output: &mut [f32]) {
+        if self.paused {
+            output.fill(0_f32);
+            return;
+        }
+        for samples in output.chunks_exact_mut(2) {
+            self.start_frame();
+            let mut mixed = [0_f32; 2];
+            let Mixer { frame, decks, voices, .. } = self;
+            voices.retain_mut(|voice| {
+                let sound = &voice.sounds[voice.instrument];
+                let frames = sound.frames();
+                if voice.position >= (frames-1) as f64 {
+                    return false;
+                }
+                let gain = decks.iter().find(|deck| deck.id == voice.deck).map_or(1_f32, |deck| deck.gain(*frame));
+                let channels = sound.channels as usize;
+                let index = voice.position as usize;
+                let fraction = (voice.position - index as f64) as f32;
+                for (channel, sample) in mixed.iter_mut().enumerate() {
+                    //resampled linearly for its speed, mono samples go to both channels
+                    let source = channel.min(channels-1);
+                    let from = sound.samples[index*channels+source];
+                    let to = sound.samples[(index+1)*channels+source];
+                    *sample += (from+(to-from)*fraction)*voice.gains[channel]*gain;
+                }
+                voice.position += voice.step;
+                true
+            });
+            samples.copy_from_slice(&mixed);
+            self.frame += 1;
+        }
+    }
+}
+
+/* feeds the output device from the mixer a block at a time, so the lock isn't taken per sample */
+#[cfg(feature = "audio")]
+struct MixerSource {
+    mixer: Arc<Mutex<Mixer>>,
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+#[cfg(feature = "audio")]
+impl Iterator for MixerSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.index >= self.buffer.len() {
+            self.mixer.lock().unwrap_or_else(PoisonError::into_inner).mix(&mut self.buffer);
+            self.index = 0;
+        }
+        self.index += 1;
+        Some(self.buffer[self.index-1])
+    }
+}
+
+#[cfg(feature = "audio")]
+impl Source for MixerSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/* keeps a mixer's time without an output device, until the player is dropped */
+fn run_clock(mixer: Weak<Mutex<Mixer>>) {
+    let start = Instant::now();
+    let mut mixed = 0_i64;
+    let mut buffer: Vec<f32> = Vec::new();
+    while let Some(mixer) = mixer.upgrade() {
+        let due = seconds_to_frames(start.elapsed().as_secs_f64(), SAMPLE_RATE);
+        buffer.resize((due-mixed) as usize * 2, 0_f32);
+        mixer.lock().unwrap_or_else(PoisonError::into_inner).mix(&mut buffer);
+        mixed = due;
+        drop(mixer);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// The playback engine: plays songs in real time on the default output device,
+/// or silently keeping time when there is none. Stops when dropped.
+///
+/// Songs get an id when they're played or queued, [`Player::take_events`] and
+/// [`Player::status`] refer to them by it.
+pub struct Player {
+    mixer: Arc<Mutex<Mixer>>,
+    #[cfg(feature = "audio")]
+    _stream: Option<OutputStream>,
+    /// Folder the instrument samples are loaded from.
+    pub sounds: PathBuf,
+    /// Why playback is silent.
+    pub output_error: Option<String>,
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Player::new()
+    }
+}
+
+impl Player {
+    /// Samples come from `sounds/`.
+    pub fn new() -> Player {
+        let mixer = Arc::new(Mutex::new(Mixer::new(SAMPLE_RATE)));
+        let sounds = PathBuf::from("sounds");
+        #[cfg(feature = "audio")]
+        let output_error = match OutputStream::try_default() {
+            Ok((stream, handle)) => {
+                let source = MixerSource { mixer: mixer.clone(), buffer: vec![0_f32; 1024], index: 1024 };
+                match handle.play_raw(source) {
+                    Ok(()) => return Player { mixer, _stream: Some(stream), sounds, output_error: None },
+                    Err(error) => error.to_string(),
+                }
+            },
+            Err(error) => error.to_string(),
+        };
+        #[cfg(not(feature = "audio"))]
+        let output_error = "built without the audio feature".to_owned();
+
+        let clock = Arc::downgrade(&mixer);
+        std::thread::spawn(move || run_clock(clock));
+        Player {
+            mixer,
+            #[cfg(feature = "audio")]
+            _stream: None,
+            sounds,
+            output_error: Some(output_error),
+        }
+    }
+
+    /* held briefly, the output waits on it */
+    fn lock(&self) -> MutexGuard<'_, Mixer> {
+        self.mixer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Loads the samples the song needs, looping the way its header asks for.
+    /// Instruments whose samples can't be loaded play silently, [`Track::warning`] says which.
+    pub fn load(&self, song: Song) -> Track {
+        let (sounds, errors) = load_sounds(&song, &self.sounds);
+        Track {
+            warning: silent_warning(&errors),
+            ..Track::new(song, sounds)
+        }
+    }
+
+    /// Cuts whatever is playing and starts the track from its beginning.
+    pub fn play(&self, id: usize, track: Track) {
+        self.lock().play(id, track);
+    }
+
+    /// Plays the track right after the current one, see [`Player::set_transition`].
+    pub fn queue(&self, track: Option<(usize, Track)>) {
+        self.lock().queue(track);
+    }
+
+    /// Hands over to the queued track now.
+    pub fn skip(&self) {
+        self.lock().skip();
+    }
+
+    pub fn set_transition(&self, transition: Transition) {
+        self.lock().set_transition(transition);
+    }
+
+    pub fn pause(&self) {
+        self.lock().pause();
+    }
+
+    pub fn resume(&self) {
+        self.lock().resume();
+    }
+
+    /// See [`Mixer::seek`].
+    pub fn seek(&self, tick: i32) {
+        self.seek_with(|id, schedule| Seek::to_tick(id, schedule, tick));
+    }
+
+    /// See [`Mixer::seek_time`].
+    pub fn seek_time(&self, seconds: f64) {
+        self.seek_with(|id, schedule| Seek::to_time(id, schedule, seconds));
+    }
+
+    /* walking the song to the seek happens outside the lock, again if the song changed meanwhile */
+    fn seek_with(&self, prepare: impl Fn(usize, &Schedule<'static>) -> Seek) {
+        loop {
+            let Some((id, schedule)) = self.lock().current_schedule() else {
+                return;
+            };
+            let seek = prepare(id, &schedule);
+            if self.lock().finish_seek(seek) {
+                return;
+            }
+        }
+    }
+
+    /// Cuts everything and forgets the queued track.
+    pub fn stop(&self) {
+        self.lock().stop();
+    }
+
+    /// See [`Mixer::edit_song`], the new length is worked out outside the lock.
+    pub fn edit_song(&self, edit: impl FnOnce(&mut Song)) {
+        let Some((id, schedule)) = self.lock().edit_current(edit) else {
+            return;
+        };
+        let length = schedule.length();
+        self.lock().set_length(id, &schedule, length);
+    }
+
+    pub fn status(&self) -> Status {
+        self.lock().status()
+    }
+
+    /// Nothing is playing and no note rings any more.
+    pub fn silent(&self) -> bool {
+        self.lock().silent()
+    }
+
+    /// Songs that started or finished since the last call.
+    pub fn take_events(&self) -> Vec<MixerEvent> {
+        self.lock().take_events()
+    }
+}
+
+/// Plays the song on the default output device, blocking until it's done and its notes stopped ringing.
+pub fn play_song(song : &Song){
+    let player = Player::new();
+    player.play(0, player.load(song.clone()));
+    while !player.silent() {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::tests::empty_song;
+
+    #[test]
+    fn seek_time_lands_on_ticks_tick_lengths_cant_hold() {
+        let mut song = empty_song(1);
+        for tick in [0, 3, 20] {
+            song.noteblocks.insert(tick, 0, Noteblock { instrument: 0, key: 45, volume: 100, panning: 100, pitch: 0 });
+        }
+        let seek_time = |seconds: f64| Schedule::new(&song).seek_time(seconds);
+        assert_eq!(seek_time(0.5), 5);
+        assert_eq!(seek_time(0.3), 3);
+        assert_eq!(seek_time(0.7), 7);
+        assert_eq!(seek_time(0.29), 2);
+        assert_eq!(seek_time(1.9), 19);
+        assert_eq!(seek_time(2.1), i32::MAX);
+    }
+}
@@ -0,0 +1,214 @@
+//! A list of song files played in order or shuffled, saved as M3U playlists.
+
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::files::{self, FileError};
+
+/// What happens when a song finishes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Repeat {
+    /// Stop after the last song.
+    #[default]
+    Off,
+    /// Play the same song again.
+    One,
+    /// Start over after the last song.
+    All,
+}
+
+impl Repeat {
+    /// Off, all, one, off...
+    pub fn next(self) -> Repeat {
+        match self {
+            Repeat::Off => Repeat::All,
+            Repeat::All => Repeat::One,
+            Repeat::One => Repeat::Off,
+        }
+    }
+}
+
+/// How one song hands over to the next.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Transition {
+    /// The next song starts the moment the last one ends.
+    #[default]
+    Gapless,
+    /// The next song fades in over the end of the last one, for this many seconds.
+    Crossfade(f64),
+}
+
+/// Whether the path looks like a playlist file.
+pub fn is_playlist(path: &Path) -> bool {
+    path.extension().and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("m3u") || extension.eq_ignore_ascii_case("m3u8"))
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Playlist {
+    pub songs: Vec<PathBuf>,
+    pub repeat: Repeat,
+    shuffle: bool,
+    /// Indices into the songs in the order they play.
+    order: Vec<usize>,
+    /// Index into the order of the current song.
+    position: Option<usize>,
+    seed: u64,
+}
+
+impl Playlist {
+    pub fn new(songs: Vec<PathBuf>) -> Playlist {
+        Playlist {
+            order: (0..songs.len()).collect(),
+            songs,
+            ..Playlist::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.songs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.songs.is_empty()
+    }
+
+    /// Where the current song is in the play order, from 0.
+    pub fn position(&self) -> Option<usize> {
+        self.position
+    }
+
+    pub fn current(&self) -> Option<&Path> {
+        self.song_at(self.position?)
+    }
+
+    /// Index in [`Playlist::songs`] of the current song.
+    pub fn current_index(&self) -> Option<usize> {
+        self.order.get(self.position?).copied()
+    }
+
+    fn song_at(&self, position: usize) -> Option<&Path> {
+        self.order.get(position).map(|&index| self.songs[index].as_path())
+    }
+
+    /// Adds a song after the last one, it's also played last when shuffled.
+    pub fn push(&mut self, song: PathBuf) {
+        self.order.push(self.songs.len());
+        self.songs.push(song);
+    }
+
+    pub fn clear(&mut self) {
+        self.songs.clear();
+        self.order.clear();
+        self.position = None;
+    }
+
+    pub fn shuffle(&self) -> bool {
+        self.shuffle
+    }
+
+    /// Shuffling mixes the songs that haven't played yet, turning it off goes back to
+    /// the list's order from the current song.
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        self.shuffle = shuffle;
+        let current = self.position.map(|position| self.order[position]);
+        self.order = (0..self.songs.len()).collect();
+        if shuffle {
+            if self.seed == 0 {
+                self.seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |time| time.as_nanos() as u64) | 1;
+            }
+            //xorshift is plenty for picking the next song
+            for index in (1..self.order.len()).rev() {
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 7;
+                self.seed ^= self.seed << 17;
+                self.order.swap(index, (self.seed % (index as u64 + 1)) as usize);
+            }
+            if let Some(current) = current {
+                //the current song goes first so every other song is still ahead
+                let at = self.order.iter().position(|&index| index == current).unwrap_or(0);
+                self.order.swap(0, at);
+            }
+        }
+        self.position = current.and_then(|current| self.order.iter().position(|&index| index == current));
+    }
+
+    /// Makes a song of the list the current one, by its index in [`Playlist::songs`].
+    pub fn select(&mut self, index: usize) -> Option<&Path> {
+        self.position = self.order.iter().position(|&song| song == index);
+        self.current()
+    }
+
+    fn after(&self, repeat: Repeat) -> Option<usize> {
+        let Some(position) = self.position else {
+            return if self.order.is_empty() { None } else { Some(0) };
+        };
+        match repeat {
+            Repeat::One => Some(position),
+            Repeat::All if position + 1 >= self.order.len() => Some(0),
+            _ if position + 1 < self.order.len() => Some(position + 1),
+            _ => None,
+        }
+    }
+
+    /// The song that plays when the current one finishes, so it can be loaded ahead of time.
+    pub fn upcoming(&self) -> Option<&Path> {
+        self.song_at(self.after(self.repeat)?)
+    }
+
+    /// Moves on to [`Playlist::upcoming`] once the current song finished.
+    pub fn advance(&mut self) -> Option<&Path> {
+        self.position = self.after(self.repeat);
+        self.current()
+    }
+
+    /// Skips to the next song, repeating one song doesn't hold this back.
+    pub fn skip(&mut self) -> Option<&Path> {
+        let repeat = if self.repeat == Repeat::One { Repeat::All } else { self.repeat };
+        self.position = Some(self.after(repeat)?);
+        self.current()
+    }
+
+    /// Goes back a song, wrapping around to the last one when repeating.
+    pub fn previous(&mut self) -> Option<&Path> {
+        let position = match self.position {
+            Some(0) | None if self.repeat != Repeat::Off => self.order.len().checked_sub(1)?,
+            Some(0) | None => return None,
+            Some(position) => position - 1,
+        };
+        self.position = Some(position);
+        self.current()
+    }
+
+    /// Reads an M3U playlist, relative paths are relative to its folder.
+    pub fn load(path: &Path) -> Result<Playlist, FileError> {
+        let input = fs::read_to_string(path).map_err(|error| FileError::Io(path.to_owned(), error))?;
+        let folder = path.parent().unwrap_or(Path::new(""));
+        let songs = input.trim_start_matches('\u{feff}').lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| folder.join(line))
+            .collect();
+        Ok(Playlist::new(songs))
+    }
+
+    /// Writes an extended M3U playlist in the list's order, songs under the
+    /// playlist's folder are saved relative to it.
+    pub fn save(&self, path: &Path) -> Result<(), FileError> {
+        let folder = path.parent().map(|folder| folder.canonicalize().unwrap_or_else(|_| folder.to_owned()));
+        let mut output = String::from("#EXTM3U\n");
+        for song in &self.songs {
+            if let Ok(header) = files::header(song) {
+                let seconds = if header.tempo > 0 { header.song_length as i64 * 100 / header.tempo as i64 } else { -1 };
+                let title = if header.author.is_empty() { header.name } else { format!("{} - {}", header.author, header.name) };
+                let _ = writeln!(output, "#EXTINF:{},{}", seconds, title);
+            }
+            let absolute = song.canonicalize().unwrap_or_else(|_| song.clone());
+            let relative = folder.as_ref().and_then(|folder| absolute.strip_prefix(folder).ok());
+            let _ = writeln!(output, "{}", relative.unwrap_or(&absolute).display());
+        }
+        fs::write(path, output).map_err(|error| FileError::Io(path.to_owned(), error))
+    }
+}
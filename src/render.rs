@@ -0,0 +1,348 @@
+//! Offline rendering of songs into audio files, faster than real time.
+//!
+//! Uses the same [`Mixer`] as real time playback, so tempo changers, loops and
+//! panning sound the same, and doesn't need the `audio` feature.
+
+use std::fmt;
+use std::fs::File;
+use std::str::FromStr;
+use std::path::{Path, PathBuf};
+
+use crate::parsers::Song;
+use crate::playback::{self, Mixer, Schedule, ScheduledNote, Track, TEMPO_CHANGER};
+
+/// How rendered samples are stored in the wav file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    Int16,
+    Float32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderOptions {
+    pub sample_rate: u32,
+    pub format: SampleFormat,
+    /// How many times a looping song jumps back to its loop start, songs that don't loop ignore it.
+    pub loops: u32,
+    /// Seconds after the end of the song that ringing notes fade out over.
+    pub fade_out: f64,
+    /// Folder the instrument samples are loaded from.
+    pub sounds: PathBuf,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            sample_rate: 44100,
+            format: SampleFormat::Int16,
+            loops: 0,
+            fade_out: 2_f64,
+            sounds: PathBuf::from("sounds"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RenderError {
+    Io(PathBuf, std::io::Error),
+    Sound(PathBuf, lewton::VorbisError),
+    Wav(hound::Error),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Io(path, error) => write!(f, "couldn't open {}: {}", path.display(), error),
+            RenderError::Sound(path, error) => write!(f, "couldn't decode {}: {}", path.display(), error),
+            RenderError::Wav(error) => write!(f, "couldn't write the wav file: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+/// A decoded instrument sample, interleaved like the file it came from.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sound {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub samples: Vec<f32>,
+}
+
+impl Sound {
+    pub fn frames(&self) -> usize {
+        if self.channels == 0 {
+            return 0;
+        }
+        self.samples.len() / self.channels as usize
+    }
+}
+
+/// Decodes an ogg vorbis file.
+pub fn load_sound(path: &Path) -> Result<Sound, RenderError> {
+    let file = File::open(path).map_err(|error| RenderError::Io(path.to_owned(), error))?;
+    let mut reader = lewton::inside_ogg::OggStreamReader::new(file).map_err(|error| RenderError::Sound(path.to_owned(), error))?;
+    let mut samples: Vec<f32> = Vec::new();
+    while let Some(packet) = reader.read_dec_packet_itl().map_err(|error| RenderError::Sound(path.to_owned(), error))? {
+        samples.extend(packet.iter().map(|sample| *sample as f32 / 32768_f32));
+    }
+    Ok(Sound {
+        sample_rate: reader.ident_hdr.audio_sample_rate,
+        channels: reader.ident_hdr.audio_channels as u16,
+        samples,
+    })
+}
+
+/// The samples of every instrument the song uses, indexed like [`playback::instruments`].
+///
+/// An instrument whose sample can't be loaded gets a silent [`Sound`] so the
+/// rest of the song still plays, the errors say which ones.
+pub fn load_sounds(song: &Song, folder: &Path) -> (Vec<Sound>, Vec<RenderError>) {
+    let mut errors: Vec<RenderError> = Vec::new();
+    let sounds = playback::instruments(song).iter().map(|instrument| {
+        if instrument.name == TEMPO_CHANGER {
+            return Sound::default(); //never played
+        }
+        load_sound(&folder.join(&instrument.sound_file)).unwrap_or_else(|error| {
+            errors.push(error);
+            Sound::default()
+        })
+    }).collect();
+    (sounds, errors)
+}
+
+/// Says which instruments [`load_sounds`] left silent, if any.
+pub fn silent_warning(errors: &[RenderError]) -> Option<String> {
+    if errors.is_empty() {
+        return None;
+    }
+    let errors: Vec<String> = errors.iter().map(RenderError::to_string).collect();
+    Some(format!("{}, those instruments are silent", errors.join("; ")))
+}
+
+/// Renders only the notes the filter lets through, the length is the same as the whole song's.
+pub fn render_filtered(song: &Song, sounds: &[Sound], options: &RenderOptions, filter: impl FnMut(&ScheduledNote) -> bool + Send + 'static) -> Vec<f32> {
+    let rate = options.sample_rate as f64;
+    let mut schedule = Schedule::with_loops(song, Some(options.loops));
+    schedule.by_ref().for_each(drop);
+    let end_frame = (schedule.end_time()*rate) as usize;
+    let fade_frames = (options.fade_out.max(0_f64)*rate) as usize;
+
+    //the same mixer real time playback uses, just not waiting for the output
+    let mut mixer = Mixer::new(options.sample_rate);
+    let track = Track::with_schedule(Schedule::owned(song.clone(), Some(options.loops)), sounds.to_vec());
+    mixer.play(0, track.with_filter(filter));
+    let mut output: Vec<f32> = vec![0_f32; (end_frame+fade_frames)*2];
+    mixer.mix(&mut output);
+
+    //fade whatever is still ringing after the song ends
+    for frame in end_frame..end_frame+fade_frames {
+        let gain = 1_f32 - (frame-end_frame) as f32 / fade_frames as f32;
+        output[frame*2] *= gain;
+        output[frame*2+1] *= gain;
+    }
+    output
+}
+
+/// Renders the whole song into interleaved stereo samples.
+pub fn render(song: &Song, sounds: &[Sound], options: &RenderOptions) -> Vec<f32> {
+    render_filtered(song, sounds, options, |_| true)
+}
+
+/// What each stem of [`render_stems`] holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StemGrouping {
+    Layer,
+    Instrument,
+}
+
+impl FromStr for StemGrouping {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "layer" => Ok(StemGrouping::Layer),
+            "instrument" => Ok(StemGrouping::Instrument),
+            _ => Err(format!("unknown grouping {}, use layer or instrument", name)),
+        }
+    }
+}
+
+/// File name pattern for stems, see [`render_stems`].
+pub const DEFAULT_STEM_PATTERN: &str = "{song} - {index} {name}.wav";
+
+/* keeps names usable as file names */
+fn file_name_part(name: &str) -> String {
+    name.chars()
+        .map(|character| if character.is_control() || "/\\:*?\"<>|".contains(character) { '_' } else { character })
+        .collect()
+}
+
+/// The files [`render_stems`] wrote.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stems {
+    pub files: Vec<PathBuf>,
+    /// Set when some samples couldn't be loaded, see [`silent_warning`].
+    pub warning: Option<String>,
+}
+
+/// Renders every layer (or instrument) that makes a sound into its own wav file in `folder`.
+///
+/// All stems have the song's full length so they line up in a DAW. In the
+/// pattern `{song}` is the song name, `{index}` the layer or instrument number
+/// starting at 1 and `{name}` its name. Instruments whose samples can't be
+/// loaded are silent like in [`render_to_wav`].
+pub fn render_stems(song: &Song, folder: &Path, pattern: &str, grouping: StemGrouping, options: &RenderOptions) -> Result<Stems, RenderError> {
+    let (sounds, errors) = load_sounds(song, &options.sounds);
+    let group = move |note: &ScheduledNote| match grouping {
+        StemGrouping::Layer => note.layer as usize,
+        StemGrouping::Instrument => note.instrument,
+    };
+
+    //silent layers and instruments don't get a file
+    let mut audible: Vec<usize> = Schedule::with_loops(song, Some(options.loops))
+        .flat_map(|tick| tick.notes)
+        .filter(|note| note.volume > 0_f32)
+        .map(|note| group(&note))
+        .collect();
+    audible.sort_unstable();
+    audible.dedup();
+
+    let instruments = playback::instruments(song);
+    let layers = playback::layers(song);
+    let song_name = if song.header.name.is_empty() { "song" } else { &song.header.name };
+    let mut written: Vec<PathBuf> = Vec::new();
+    for index in audible {
+        let name = match grouping {
+            StemGrouping::Layer => layers.get(index).map_or(String::new(), |layer| layer.name.clone()),
+            StemGrouping::Instrument => instruments.get(index).map_or(String::new(), |instrument| instrument.name.clone()),
+        };
+        let name = if name.is_empty() {
+            match grouping {
+                StemGrouping::Layer => format!("Layer {}", index+1),
+                StemGrouping::Instrument => format!("Instrument {}", index+1),
+            }
+        } else {
+            name
+        };
+        let file_name = pattern
+            .replace("{song}", &file_name_part(song_name))
+            .replace("{index}", &(index+1).to_string())
+            .replace("{name}", &file_name_part(&name));
+        let path = folder.join(file_name);
+        write_wav(&path, &render_filtered(song, &sounds, options, move |note| group(note) == index), options)?;
+        written.push(path);
+    }
+    Ok(Stems { files: written, warning: silent_warning(&errors) })
+}
+
+/// Writes interleaved stereo samples to a wav file.
+pub fn write_wav(path: &Path, samples: &[f32], options: &RenderOptions) -> Result<(), RenderError> {
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: options.sample_rate,
+        bits_per_sample: match options.format {
+            SampleFormat::Int16 => 16,
+            SampleFormat::Float32 => 32,
+        },
+        sample_format: match options.format {
+            SampleFormat::Int16 => hound::SampleFormat::Int,
+            SampleFormat::Float32 => hound::SampleFormat::Float,
+        },
+    };
+    let mut writer = hound::WavWriter::create(path, spec).map_err(RenderError::Wav)?;
+    for sample in samples {
+        match options.format {
+            SampleFormat::Int16 => writer.write_sample((sample.clamp(-1_f32, 1_f32)*i16::MAX as f32) as i16),
+            SampleFormat::Float32 => writer.write_sample(*sample),
+        }.map_err(RenderError::Wav)?;
+    }
+    writer.finalize().map_err(RenderError::Wav)
+}
+
+/// Loads the song's samples, renders it and writes it to a wav file.
+///
+/// Returns a warning when some samples couldn't be loaded, see [`silent_warning`].
+pub fn render_to_wav(song: &Song, path: &Path, options: &RenderOptions) -> Result<Option<String>, RenderError> {
+    let (sounds, errors) = load_sounds(song, &options.sounds);
+    write_wav(path, &render(song, &sounds, options), options)?;
+    Ok(silent_warning(&errors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::{tests::empty_song, Noteblock};
+
+    /* harp notes on ticks 0 and 10 at 10 ticks per second, the song ends with its bar at 1.6s */
+    fn two_notes() -> Song {
+        let mut song = empty_song(1);
+        for tick in [0, 10] {
+            song.noteblocks.insert(tick, 0, Noteblock { instrument: 0, key: 45, volume: 100, panning: 100, pitch: 0 });
+        }
+        song
+    }
+
+    /* a steady tone for the harp, longer than anything rendered, played at its own speed */
+    fn steady_sounds(song: &Song) -> Vec<Sound> {
+        let mut sounds = vec![Sound::default(); playback::instruments(song).len()];
+        sounds[0] = Sound { sample_rate: 1000, channels: 1, samples: vec![0.25_f32; 20000] };
+        sounds
+    }
+
+    fn options(loops: u32, fade_out: f64) -> RenderOptions {
+        RenderOptions { sample_rate: 1000, loops, fade_out, ..RenderOptions::default() }
+    }
+
+    #[test]
+    fn output_lasts_until_the_end_plus_the_fade() {
+        let song = two_notes();
+        let sounds = steady_sounds(&song);
+        assert_eq!(render(&song, &sounds, &options(0, 0.5)).len(), (1600 + 500) * 2);
+        assert_eq!(render(&song, &sounds, &options(0, 0_f64)).len(), 1600 * 2);
+        //songs that don't loop ignore the loops
+        assert_eq!(render(&song, &sounds, &options(2, 0.5)).len(), (1600 + 500) * 2);
+    }
+
+    #[test]
+    fn loops_play_the_song_again() {
+        let mut song = two_notes();
+        song.header.looping = 1;
+        let sounds = steady_sounds(&song);
+        assert_eq!(render(&song, &sounds, &options(0, 0.5)).len(), (1600 + 500) * 2);
+        assert_eq!(render(&song, &sounds, &options(1, 0.5)).len(), (3200 + 500) * 2);
+        assert_eq!(render(&song, &sounds, &options(2, 0.5)).len(), (4800 + 500) * 2);
+    }
+
+    #[test]
+    fn stems_say_which_samples_are_missing() {
+        let mut song = two_notes();
+        song.header.layer_count = 2;
+        song.noteblocks.insert(3, 1, Noteblock { instrument: 0, key: 45, volume: 0, panning: 100, pitch: 0 });
+        let folder = std::env::temp_dir().join(format!("nbs_stems_{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let options = RenderOptions { sounds: folder.join("no sounds"), ..options(0, 0_f64) };
+        let stems = render_stems(&song, &folder, DEFAULT_STEM_PATTERN, StemGrouping::Layer, &options).unwrap();
+        std::fs::remove_dir_all(&folder).unwrap();
+        //the silent layer gets no file
+        assert_eq!(stems.files, vec![folder.join("song - 1 default_layer_0.wav")]);
+        assert!(stems.warning.is_some_and(|warning| warning.contains("harp.ogg")));
+    }
+
+    #[test]
+    fn the_tail_fades_to_silence() {
+        let song = two_notes();
+        let output = render(&song, &steady_sounds(&song), &options(0, 0.5));
+        //both notes still ring when the song ends
+        let level = output[1599 * 2];
+        assert!(level > 0.1, "level {}", level);
+        for frame in 1600..2100 {
+            let gain = 1_f32 - (frame - 1600) as f32 / 500_f32;
+            for channel in 0..2 {
+                let sample = output[frame * 2 + channel];
+                assert!((sample - level * gain).abs() < 1e-4, "frame {}: {} instead of {}", frame, sample, level * gain);
+            }
+        }
+        assert!(output[2099 * 2].abs() < level / 100_f32);
+    }
+}
@@ -0,0 +1,365 @@
+//! Export of songs as Sponge schematics (`.schem`) of playable note block builds.
+//!
+//! The build is a line of repeaters running east from a button, with note
+//! blocks branching off north and south wherever a tick has notes. Paste it,
+//! press the button and it plays the song once.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::parsers::Song;
+use crate::playback;
+
+/// Minecraft 1.20.1, the schematic loads in that version or any later one.
+pub const DATA_VERSION: i32 = 3465;
+
+/// Lowest key a note block plays, with no right clicks.
+pub const LOWEST_KEY: i8 = 33;
+/// Highest key a note block plays, after 24 right clicks.
+pub const HIGHEST_KEY: i8 = 57;
+
+/* a node powers up to 8 note blocks and a tick can chain 5 nodes before the dust runs out */
+const NODE_NOTES: usize = 8;
+const TICK_NODES: usize = 5;
+
+/// The note block `instrument` property and the block that has to be under it, for each vanilla instrument.
+pub const INSTRUMENT_BLOCKS: [(&str, &str); 16] = [
+    ("harp", "minecraft:dirt"),
+    ("bass", "minecraft:oak_planks"),
+    ("basedrum", "minecraft:stone"),
+    ("snare", "minecraft:sand"),
+    ("hat", "minecraft:glass"),
+    ("guitar", "minecraft:white_wool"),
+    ("flute", "minecraft:clay"),
+    ("bell", "minecraft:gold_block"),
+    ("chime", "minecraft:packed_ice"),
+    ("xylophone", "minecraft:bone_block"),
+    ("iron_xylophone", "minecraft:iron_block"),
+    ("cow_bell", "minecraft:soul_sand"),
+    ("didgeridoo", "minecraft:pumpkin"),
+    ("bit", "minecraft:emerald_block"),
+    ("banjo", "minecraft:hay_block"),
+    ("pling", "minecraft:glowstone"),
+];
+
+#[derive(Debug)]
+pub enum SchematicError {
+    /// Ticks per second that aren't 10 divided by a whole number, repeaters only delay in tenths of a second.
+    Tempo { tick: i32, tempo: f64 },
+    Key { tick: i32, layer: i32, key: i32 },
+    Instrument { tick: i32, layer: i32, instrument: i8 },
+    Chord { tick: i32, notes: usize },
+    /// The build is longer than the 32767 blocks a schematic can store.
+    Width(i32),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for SchematicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchematicError::Tempo { tick, tempo } => write!(f, "the tempo of {:.2} ticks per second at tick {} can't be built with repeaters, it has to be 10 divided by a whole number (10, 5, 3.33, 2.5...)", tempo, tick),
+            SchematicError::Key { tick, layer, key } => write!(f, "the note at tick {} on layer {} has key {}, note blocks only play keys {} to {} (F#3 to F#5)", tick, layer, key, LOWEST_KEY, HIGHEST_KEY),
+            SchematicError::Instrument { tick, layer, instrument } => write!(f, "the note at tick {} on layer {} uses custom instrument {}, only vanilla instruments have note block sounds", tick, layer, instrument),
+            SchematicError::Chord { tick, notes } => write!(f, "tick {} has {} notes, at most {} fit in one tick", tick, notes, NODE_NOTES*TICK_NODES),
+            SchematicError::Width(width) => write!(f, "the build is {} blocks long, a schematic holds at most {}", width, i16::MAX),
+            SchematicError::Io(error) => write!(f, "couldn't compress the schematic: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for SchematicError {}
+
+/* redstone ticks per song tick, when the tempo is buildable */
+fn redstone_ticks(tick: i32, tempo: f64) -> Result<u32, SchematicError> {
+    let ticks = (10_f64 / tempo).round();
+    if ticks < 1_f64 || (10_f64 / ticks - tempo).abs() > 0.01 {
+        return Err(SchematicError::Tempo { tick, tempo });
+    }
+    Ok(ticks as u32)
+}
+
+/* a tick with notes, its time in redstone ticks and (instrument, right clicks) of its notes */
+struct NoteTick {
+    time: u32,
+    notes: Vec<(usize, u8)>,
+}
+
+fn note_ticks(song: &Song) -> Result<Vec<NoteTick>, SchematicError> {
+    let instruments = playback::instruments(song);
+    let tempo_changer = playback::tempo_changer_index(&instruments);
+    let vanilla_count = (song.header.vanilla_instrument_count.max(0) as usize).min(INSTRUMENT_BLOCKS.len());
+
+    let mut delay = redstone_ticks(0, song.header.tempo as f64 / 100_f64)?;
+    let mut time: u32 = 0;
+    let mut last_tick = song.noteblocks.first_tick().unwrap_or(0);
+    let mut ticks: Vec<NoteTick> = Vec::new();
+    for (tick, layers) in song.noteblocks.ticks(..) {
+        time += (tick - last_tick) as u32 * delay;
+        last_tick = tick;
+        let mut notes: Vec<(usize, u8)> = Vec::new();
+        for (layer, noteblock) in layers {
+            if noteblock.instrument == tempo_changer {
+                if noteblock.pitch > 0 {
+                    delay = redstone_ticks(tick, noteblock.pitch as f64 / 15_f64)?;
+                }
+                continue;
+            }
+            if noteblock.volume <= 0 {
+                continue; //silent anyway
+            }
+            if noteblock.instrument < 0 || noteblock.instrument as usize >= vanilla_count {
+                return Err(SchematicError::Instrument { tick, layer: *layer, instrument: noteblock.instrument });
+            }
+            let key = noteblock.key as i32 + (noteblock.pitch as f64 / 100_f64).round() as i32;
+            if key < LOWEST_KEY as i32 || key > HIGHEST_KEY as i32 {
+                return Err(SchematicError::Key { tick, layer: *layer, key });
+            }
+            notes.push((noteblock.instrument as usize, (key - LOWEST_KEY as i32) as u8));
+        }
+        if notes.len() > NODE_NOTES*TICK_NODES {
+            return Err(SchematicError::Chord { tick, notes: notes.len() });
+        }
+        if !notes.is_empty() {
+            ticks.push(NoteTick { time, notes });
+        }
+    }
+    Ok(ticks)
+}
+
+enum Cell {
+    Button,
+    Repeater(u32),
+    Wire,
+    Node(Vec<(usize, u8)>),
+}
+
+/* where a node's notes go, the first one of each side is powered by the branch repeater and sets off the rest */
+const NOTE_POSITIONS: [(i32, i32); NODE_NOTES] = [(0, 2), (-1, 2), (1, 2), (0, 3), (0, -2), (-1, -2), (1, -2), (0, -3)];
+
+fn cells(ticks: Vec<NoteTick>) -> Vec<Cell> {
+    let mut cells: Vec<Cell> = vec![Cell::Button];
+    let mut last_time: u32 = 0;
+    for (index, NoteTick { time, notes }) in ticks.into_iter().enumerate() {
+        let mut delay = time - last_time;
+        let mut gap = 0;
+        while delay > 0 {
+            cells.push(Cell::Repeater(delay.min(4)));
+            delay -= delay.min(4);
+            gap += 1;
+        }
+        //nodes need two cells between them or their note blocks touch
+        while index > 0 && gap < 2 {
+            cells.push(Cell::Wire);
+            gap += 1;
+        }
+        for (node, chunk) in notes.chunks(NODE_NOTES).enumerate() {
+            if node > 0 {
+                cells.push(Cell::Wire);
+                cells.push(Cell::Wire);
+            }
+            cells.push(Cell::Node(chunk.to_vec()));
+        }
+        last_time = time;
+    }
+    cells
+}
+
+fn wire(east: bool, west: bool, north: bool, south: bool) -> String {
+    let side = |connected: bool| if connected { "side" } else { "none" };
+    format!("minecraft:redstone_wire[east={},north={},power=0,south={},west={}]", side(east), side(north), side(south), side(west))
+}
+
+/* block states by (x, y, z), z is shifted so the northmost note blocks are at 0 */
+fn blocks(cells: &[Cell]) -> BTreeMap<(i32, i32, i32), String> {
+    let mut blocks: BTreeMap<(i32, i32, i32), String> = BTreeMap::new();
+    let spine = 3;
+    for (x, cell) in cells.iter().enumerate() {
+        let x = x as i32;
+        blocks.insert((x, 1, spine), "minecraft:stone".to_owned());
+        let state = match cell {
+            Cell::Button => "minecraft:stone_button[face=floor,facing=east,powered=false]".to_owned(),
+            Cell::Repeater(delay) => format!("minecraft:repeater[delay={},facing=west,locked=false,powered=false]", delay),
+            Cell::Wire => wire(true, true, false, false),
+            Cell::Node(notes) => {
+                let mut south = false;
+                let mut north = false;
+                for ((instrument, clicks), (dx, dz)) in notes.iter().zip(NOTE_POSITIONS) {
+                    let (name, block) = INSTRUMENT_BLOCKS[*instrument];
+                    let z = spine + dz;
+                    blocks.insert((x+dx, 2, z), format!("minecraft:note_block[instrument={},note={},powered=false]", name, clicks));
+                    blocks.insert((x+dx, 1, z), block.to_owned());
+                    if block == "minecraft:sand" {
+                        blocks.insert((x+dx, 0, z), "minecraft:stone".to_owned()); //sand falls
+                    }
+                    south |= dz > 0;
+                    north |= dz < 0;
+                }
+                if south {
+                    blocks.insert((x, 2, spine+1), "minecraft:repeater[delay=1,facing=north,locked=false,powered=false]".to_owned());
+                    blocks.insert((x, 1, spine+1), "minecraft:stone".to_owned());
+                }
+                if north {
+                    blocks.insert((x, 2, spine-1), "minecraft:repeater[delay=1,facing=south,locked=false,powered=false]".to_owned());
+                    blocks.insert((x, 1, spine-1), "minecraft:stone".to_owned());
+                }
+                wire(true, true, north, south)
+            },
+        };
+        blocks.insert((x, 2, spine), state);
+    }
+    blocks
+}
+
+/* just enough NBT for a schematic, names and payloads are big endian */
+fn nbt_name(output: &mut Vec<u8>, kind: u8, name: &str) {
+    output.push(kind);
+    output.extend((name.len() as u16).to_be_bytes());
+    output.extend(name.as_bytes());
+}
+
+fn nbt_int(output: &mut Vec<u8>, name: &str, value: i32) {
+    nbt_name(output, 3, name);
+    output.extend(value.to_be_bytes());
+}
+
+fn nbt_short(output: &mut Vec<u8>, name: &str, value: i16) {
+    nbt_name(output, 2, name);
+    output.extend(value.to_be_bytes());
+}
+
+/// The uncompressed NBT of the schematic.
+pub fn schematic_nbt(song: &Song) -> Result<Vec<u8>, SchematicError> {
+    let blocks = blocks(&cells(note_ticks(song)?));
+    let width = blocks.keys().map(|(x, _, _)| x + 1).max().unwrap_or(1);
+    let stored_width = i16::try_from(width).map_err(|_| SchematicError::Width(width))?;
+    let height = 4; //air above the note blocks so they sound
+    let length = 7;
+
+    let mut palette: Vec<String> = vec!["minecraft:air".to_owned()];
+    let mut block_data: Vec<u8> = Vec::new();
+    for y in 0..height {
+        for z in 0..length {
+            for x in 0..width {
+                let state = blocks.get(&(x, y, z)).map_or("minecraft:air", |state| state.as_str());
+                let index = match palette.iter().position(|entry| entry == state) {
+                    Some(index) => index,
+                    None => {
+                        palette.push(state.to_owned());
+                        palette.len() - 1
+                    },
+                };
+                //varint
+                let mut value = index as u32;
+                while value >= 0x80 {
+                    block_data.push((value & 0x7f) as u8 | 0x80);
+                    value >>= 7;
+                }
+                block_data.push(value as u8);
+            }
+        }
+    }
+
+    let mut output: Vec<u8> = Vec::new();
+    nbt_name(&mut output, 10, "Schematic");
+    nbt_int(&mut output, "Version", 2);
+    nbt_int(&mut output, "DataVersion", DATA_VERSION);
+    nbt_short(&mut output, "Width", stored_width);
+    nbt_short(&mut output, "Height", height as i16);
+    nbt_short(&mut output, "Length", length as i16);
+    nbt_name(&mut output, 11, "Offset");
+    output.extend(3_i32.to_be_bytes());
+    output.extend([0_u8; 12]);
+    nbt_int(&mut output, "PaletteMax", palette.len() as i32);
+    nbt_name(&mut output, 10, "Palette");
+    for (index, state) in palette.iter().enumerate() {
+        nbt_int(&mut output, state, index as i32);
+    }
+    output.push(0);
+    nbt_name(&mut output, 7, "BlockData");
+    output.extend((block_data.len() as i32).to_be_bytes());
+    output.extend(block_data);
+    nbt_name(&mut output, 9, "BlockEntities");
+    output.push(10);
+    output.extend(0_i32.to_be_bytes());
+    nbt_name(&mut output, 10, "Metadata");
+    if !song.header.name.is_empty() {
+        nbt_name(&mut output, 8, "Name");
+        output.extend((song.header.name.len() as u16).to_be_bytes());
+        output.extend(song.header.name.as_bytes());
+    }
+    output.push(0);
+    output.push(0);
+    Ok(output)
+}
+
+/// The gzipped `.schem` file of the song's note block build.
+///
+/// Songs are rejected when their tempo isn't a whole number of redstone ticks per
+/// song tick, a key (with its pitch rounded to semitones) is out of note block range,
+/// a note uses a custom instrument or a tick has more notes than fit. Note volume
+/// and panning can't be built, every note plays at full volume from its block.
+pub fn export(song: &Song) -> Result<Vec<u8>, SchematicError> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&schematic_nbt(song)?).map_err(SchematicError::Io)?;
+    encoder.finish().map_err(SchematicError::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::{tests::empty_song, Noteblock};
+
+    fn harp(key: i8, pitch: i16) -> Noteblock {
+        Noteblock { instrument: 0, key, volume: 100, panning: 100, pitch }
+    }
+
+    fn contains(haystack: &[u8], needle: &str) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle.as_bytes())
+    }
+
+    #[test]
+    fn tempos_have_to_be_whole_redstone_ticks() {
+        let mut song = empty_song(1);
+        song.noteblocks.insert(0, 0, harp(45, 0));
+        for tempo in [1000, 500, 333, 250] {
+            song.header.tempo = tempo;
+            assert!(schematic_nbt(&song).is_ok(), "tempo {}", tempo);
+        }
+        for tempo in [700, 1200] {
+            song.header.tempo = tempo;
+            assert!(matches!(schematic_nbt(&song), Err(SchematicError::Tempo { tick: 0, .. })), "tempo {}", tempo);
+        }
+    }
+
+    #[test]
+    fn keys_outside_note_block_range_are_rejected() {
+        let mut song = empty_song(2);
+        song.noteblocks.insert(0, 0, harp(LOWEST_KEY, 0));
+        song.noteblocks.insert(3, 1, harp(HIGHEST_KEY, 0));
+        let nbt = schematic_nbt(&song).unwrap();
+        assert!(contains(&nbt, "minecraft:note_block[instrument=harp,note=0,powered=false]"));
+        assert!(contains(&nbt, "minecraft:note_block[instrument=harp,note=24,powered=false]"));
+
+        song.noteblocks.insert(5, 1, harp(HIGHEST_KEY + 1, 0));
+        assert!(matches!(schematic_nbt(&song), Err(SchematicError::Key { tick: 5, layer: 1, key: 58 })));
+        //pitch counts in whole semitones
+        song.noteblocks.insert(5, 1, harp(LOWEST_KEY, -100));
+        assert!(matches!(schematic_nbt(&song), Err(SchematicError::Key { tick: 5, layer: 1, key: 32 })));
+        song.noteblocks.insert(5, 1, harp(LOWEST_KEY, -40));
+        assert!(schematic_nbt(&song).is_ok());
+        //silent notes aren't built
+        song.noteblocks.insert(5, 1, Noteblock { volume: 0, ..harp(0, 0) });
+        assert!(schematic_nbt(&song).is_ok());
+    }
+
+    #[test]
+    fn custom_instruments_are_rejected() {
+        let mut song = empty_song(1);
+        song.noteblocks.insert(2, 0, Noteblock { instrument: 16, ..harp(45, 0) });
+        assert!(matches!(schematic_nbt(&song), Err(SchematicError::Instrument { tick: 2, layer: 0, instrument: 16 })));
+    }
+}
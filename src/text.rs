@@ -0,0 +1,417 @@
+//! A plain text form of songs that diffs well, for keeping songs in git.
+//!
+//! The header comes first with one field per line, then the layers, the
+//! custom instruments and one line per note block sorted by tick and layer.
+//! Text is quoted, everything else is a number, `#` starts a comment line:
+//!
+//! ```text
+//! [header]
+//! open_nbs_version 5
+//! name "Nyan Cat"
+//! ...
+//! [layers]
+//! # name locked volume stereo
+//! "Melody" 0 100 100
+//! [instruments]
+//! # name sound_file sound_key press_key
+//! "Piano" "piano.ogg" 45 1
+//! [notes]
+//! # tick layer instrument key volume panning pitch
+//! 0 0 0 45 100 100 0
+//! ```
+//!
+//! A tick on its own line is a tick the file keeps without notes, so songs
+//! round-trip to the same `.nbs` bytes.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::notes::Notes;
+use crate::parsers::{Header, Instrument, Layer, Noteblock, Song};
+
+/// Extension of songs in the text format.
+pub const EXTENSION: &str = "nbst";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextError {
+    /// Starting at 1, 0 when the whole file is at fault.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for TextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            return write!(f, "{}", self.message);
+        }
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for TextError {}
+
+pub(crate) fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for character in text.chars() {
+        match character {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            character if character.is_control() => { let _ = write!(quoted, "\\u{{{:x}}}", character as u32); },
+            character => quoted.push(character),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Writes the song as text, the same song always gives the same text.
+pub fn to_text(song: &Song) -> String {
+    let header = &song.header;
+    let mut text = String::from("# Note Block Studio song\n[header]\n");
+    let fields: [(&str, String); 21] = [
+        ("open_nbs_version", header.open_nbs_version.to_string()),
+        ("vanilla_instrument_count", header.vanilla_instrument_count.to_string()),
+        ("song_length", header.song_length.to_string()),
+        ("layer_count", header.layer_count.to_string()),
+        ("name", quote(&header.name)),
+        ("author", quote(&header.author)),
+        ("orig_author", quote(&header.orig_author)),
+        ("description", quote(&header.description)),
+        ("tempo", header.tempo.to_string()),
+        ("auto_save", header.auto_save.to_string()),
+        ("auto_save_period", header.auto_save_period.to_string()),
+        ("time_signature", header.time_signature.to_string()),
+        ("minutes_spent", header.minutes_spent.to_string()),
+        ("left_clicks", header.left_clicks.to_string()),
+        ("right_clicks", header.right_clicks.to_string()),
+        ("noteblocks_added", header.noteblocks_added.to_string()),
+        ("noteblocks_removed", header.noteblocks_removed.to_string()),
+        ("original_file_name", quote(&header.original_file_name)),
+        ("looping", header.looping.to_string()),
+        ("loop_count", header.loop_count.to_string()),
+        ("loop_start_tick", header.loop_start_tick.to_string()),
+    ];
+    for (name, value) in fields {
+        let _ = writeln!(text, "{} {}", name, value);
+    }
+
+    text.push_str("[layers]\n# name locked volume stereo\n");
+    for layer in &song.layers {
+        let _ = writeln!(text, "{} {} {} {}", quote(&layer.name), layer.locked, layer.volume, layer.stereo);
+    }
+    text.push_str("[instruments]\n# name sound_file sound_key press_key\n");
+    for instrument in &song.custom_instruments {
+        let _ = writeln!(text, "{} {} {} {}", quote(&instrument.name), quote(&instrument.sound_file), instrument.sound_key, instrument.press_key);
+    }
+    text.push_str("[notes]\n# tick layer instrument key volume panning pitch\n");
+    for (tick, layers) in song.noteblocks.ticks(..) {
+        if layers.is_empty() {
+            let _ = writeln!(text, "{}", tick);
+        }
+        for (layer, noteblock) in layers {
+            let _ = writeln!(text, "{} {} {} {} {} {} {}", tick, layer, noteblock.instrument, noteblock.key, noteblock.volume, noteblock.panning, noteblock.pitch);
+        }
+    }
+    text
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Token {
+    Word(String),
+    Text(String),
+}
+
+pub(crate) fn tokens(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut characters = line.chars().peekable();
+    while let Some(character) = characters.next() {
+        if character.is_whitespace() {
+            continue;
+        }
+        if character != '"' {
+            let mut word = String::from(character);
+            while let Some(next) = characters.next_if(|next| !next.is_whitespace()) {
+                word.push(next);
+            }
+            tokens.push(Token::Word(word));
+            continue;
+        }
+        let mut text = String::new();
+        loop {
+            match characters.next() {
+                None => return Err("missing the closing quote".to_owned()),
+                Some('"') => break,
+                Some('\\') => match characters.next() {
+                    Some('"') => text.push('"'),
+                    Some('\\') => text.push('\\'),
+                    Some('n') => text.push('\n'),
+                    Some('r') => text.push('\r'),
+                    Some('t') => text.push('\t'),
+                    Some('u') => {
+                        let mut code = String::new();
+                        if characters.next() != Some('{') {
+                            return Err("expected { after \\u".to_owned());
+                        }
+                        for next in characters.by_ref() {
+                            if next == '}' {
+                                break;
+                            }
+                            code.push(next);
+                        }
+                        let character = u32::from_str_radix(&code, 16).ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("\\u{{{}}} isn't a character", code))?;
+                        text.push(character);
+                    },
+                    other => return Err(format!("unknown escape \\{}", other.map_or(String::new(), String::from))),
+                },
+                Some(character) => text.push(character),
+            }
+        }
+        tokens.push(Token::Text(text));
+    }
+    Ok(tokens)
+}
+
+pub(crate) fn number<T: FromStr>(token: &Token, name: &str) -> Result<T, String> {
+    match token {
+        Token::Word(word) => word.parse().map_err(|_| format!("{} should be a number that fits, not {}", name, word)),
+        Token::Text(_) => Err(format!("{} should be a number, not text", name)),
+    }
+}
+
+pub(crate) fn text(token: &Token, name: &str) -> Result<String, String> {
+    match token {
+        Token::Text(text) => Ok(text.clone()),
+        Token::Word(word) => Err(format!("{} should be quoted text, not {}", name, word)),
+    }
+}
+
+/* the values of a line, checking there are as many as the section has columns */
+fn columns<'a>(tokens: &'a [Token], names: &[&str]) -> Result<&'a [Token], String> {
+    if tokens.len() != names.len() {
+        return Err(format!("expected {} values ({}), found {}", names.len(), names.join(" "), tokens.len()));
+    }
+    Ok(tokens)
+}
+
+/* header fields are taken out as they're used, whatever is left over is unknown */
+fn header_field(fields: &mut HashMap<String, (usize, Token)>, name: &str) -> Result<(usize, Token), TextError> {
+    fields.remove(name).ok_or_else(|| TextError { line: 0, message: format!("the header is missing {}", name) })
+}
+
+fn header_number<T: FromStr>(fields: &mut HashMap<String, (usize, Token)>, name: &str) -> Result<T, TextError> {
+    let (line, token) = header_field(fields, name)?;
+    number(&token, name).map_err(|message| TextError { line, message })
+}
+
+fn header_text(fields: &mut HashMap<String, (usize, Token)>, name: &str) -> Result<String, TextError> {
+    let (line, token) = header_field(fields, name)?;
+    text(&token, name).map_err(|message| TextError { line, message })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Section {
+    None,
+    Header,
+    Layers,
+    Instruments,
+    Notes,
+}
+
+/// Reads a song written by [`to_text`].
+pub fn from_text(input: &str) -> Result<Song, TextError> {
+    let mut section = Section::None;
+    let mut fields: HashMap<String, (usize, Token)> = HashMap::new();
+    let mut layers: Vec<Layer> = Vec::new();
+    let mut custom_instruments: Vec<Instrument> = Vec::new();
+    let mut notes = Notes::new();
+    let mut empty_ticks: Vec<i32> = Vec::new();
+
+    for (index, line) in input.lines().enumerate() {
+        let line_number = index + 1;
+        let error = |message: String| TextError { line: line_number, message };
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if trimmed.starts_with('[') {
+            section = match trimmed {
+                "[header]" => Section::Header,
+                "[layers]" => Section::Layers,
+                "[instruments]" => Section::Instruments,
+                "[notes]" => Section::Notes,
+                _ => return Err(error(format!("unknown section {}", trimmed))),
+            };
+            continue;
+        }
+        let tokens = tokens(trimmed).map_err(error)?;
+        match section {
+            Section::None => return Err(error("expected a section like [header] first".to_owned())),
+            Section::Header => {
+                let [Token::Word(name), value] = &tokens[..] else {
+                    return Err(error("expected a field name and its value".to_owned()));
+                };
+                if fields.insert(name.clone(), (line_number, value.clone())).is_some() {
+                    return Err(error(format!("{} is set twice", name)));
+                }
+            },
+            Section::Layers => {
+                let values = columns(&tokens, &["name", "locked", "volume", "stereo"]).map_err(error)?;
+                layers.push(Layer {
+                    name: text(&values[0], "name").map_err(error)?,
+                    locked: number(&values[1], "locked").map_err(error)?,
+                    volume: number(&values[2], "volume").map_err(error)?,
+                    stereo: number(&values[3], "stereo").map_err(error)?,
+                });
+            },
+            Section::Instruments => {
+                let values = columns(&tokens, &["name", "sound_file", "sound_key", "press_key"]).map_err(error)?;
+                custom_instruments.push(Instrument {
+                    name: text(&values[0], "name").map_err(error)?,
+                    sound_file: text(&values[1], "sound_file").map_err(error)?,
+                    sound_key: number(&values[2], "sound_key").map_err(error)?,
+                    press_key: number(&values[3], "press_key").map_err(error)?,
+                });
+            },
+            Section::Notes => {
+                if tokens.len() == 1 {
+                    empty_ticks.push(number(&tokens[0], "tick").map_err(error)?);
+                    continue;
+                }
+                let values = columns(&tokens, &["tick", "layer", "instrument", "key", "volume", "panning", "pitch"]).map_err(error)?;
+                let tick: i32 = number(&values[0], "tick").map_err(error)?;
+                let layer: i32 = number(&values[1], "layer").map_err(error)?;
+                let noteblock = Noteblock {
+                    instrument: number(&values[2], "instrument").map_err(error)?,
+                    key: number(&values[3], "key").map_err(error)?,
+                    volume: number(&values[4], "volume").map_err(error)?,
+                    panning: number(&values[5], "panning").map_err(error)?,
+                    pitch: number(&values[6], "pitch").map_err(error)?,
+                };
+                if notes.insert(tick, layer, noteblock).is_some() {
+                    return Err(error(format!("there's already a note at tick {} on layer {}", tick, layer)));
+                }
+            },
+        }
+    }
+
+    let header = Header {
+        open_nbs_version: header_number(&mut fields, "open_nbs_version")?,
+        vanilla_instrument_count: header_number(&mut fields, "vanilla_instrument_count")?,
+        song_length: header_number(&mut fields, "song_length")?,
+        layer_count: header_number(&mut fields, "layer_count")?,
+        name: header_text(&mut fields, "name")?,
+        author: header_text(&mut fields, "author")?,
+        orig_author: header_text(&mut fields, "orig_author")?,
+        description: header_text(&mut fields, "description")?,
+        tempo: header_number(&mut fields, "tempo")?,
+        auto_save: header_number(&mut fields, "auto_save")?,
+        auto_save_period: header_number(&mut fields, "auto_save_period")?,
+        time_signature: header_number(&mut fields, "time_signature")?,
+        minutes_spent: header_number(&mut fields, "minutes_spent")?,
+        left_clicks: header_number(&mut fields, "left_clicks")?,
+        right_clicks: header_number(&mut fields, "right_clicks")?,
+        noteblocks_added: header_number(&mut fields, "noteblocks_added")?,
+        noteblocks_removed: header_number(&mut fields, "noteblocks_removed")?,
+        original_file_name: header_text(&mut fields, "original_file_name")?,
+        looping: header_number(&mut fields, "looping")?,
+        loop_count: header_number(&mut fields, "loop_count")?,
+        loop_start_tick: header_number(&mut fields, "loop_start_tick")?,
+    };
+    if let Some((name, (line, _))) = fields.into_iter().min_by_key(|(_, (line, _))| *line) {
+        return Err(TextError { line, message: format!("unknown header field {}", name) });
+    }
+
+    for tick in empty_ticks {
+        notes.insert_tick(tick);
+    }
+
+    let song = Song { header, noteblocks: notes, layers, custom_instruments };
+    crate::parsers::validate(&song).map_err(|expected| TextError { line: 0, message: format!("invalid song: expected {}", expected) })?;
+    Ok(song)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parsers, writers};
+
+    /* strings that need escaping, a custom instrument in use and ticks the file keeps without notes */
+    fn sample_song() -> Song {
+        let mut noteblocks = Notes::new();
+        noteblocks.insert(0, 0, Noteblock { instrument: 0, key: 45, volume: 100, panning: 100, pitch: 0 });
+        noteblocks.insert(0, 2, Noteblock { instrument: 16, key: 52, volume: 60, panning: 30, pitch: -50 });
+        noteblocks.insert_tick(4);
+        noteblocks.insert(9, 1, Noteblock { instrument: 3, key: 33, volume: 100, panning: 200, pitch: 12 });
+        noteblocks.insert_tick(12);
+        Song {
+            header: Header {
+                open_nbs_version: 5,
+                vanilla_instrument_count: 16,
+                song_length: 12,
+                layer_count: 3,
+                name: "\"Quoted\" \\ back\\slashes".into(),
+                author: "two\nlines\tand a tab".into(),
+                orig_author: "bell \u{7} and \r".into(),
+                description: "# not a comment [header]".into(),
+                tempo: 1250,
+                auto_save: 1,
+                auto_save_period: 5,
+                time_signature: 3,
+                minutes_spent: 42,
+                left_clicks: 1000,
+                right_clicks: 20,
+                noteblocks_added: 300,
+                noteblocks_removed: 7,
+                original_file_name: "C:\\songs\\tune.mid".into(),
+                looping: 1,
+                loop_count: 0,
+                loop_start_tick: 4,
+            },
+            noteblocks,
+            layers: vec![
+                Layer { name: "Melody".into(), locked: 0, volume: 100, stereo: 100 },
+                Layer { name: "\"bass\"".into(), locked: 1, volume: 80, stereo: 0 },
+                Layer { name: String::new(), locked: 0, volume: 50, stereo: 200 },
+            ],
+            custom_instruments: vec![Instrument { name: "Piano \"grand\"".into(), sound_file: "piano\\low.ogg".into(), sound_key: 45, press_key: 1 }],
+        }
+    }
+
+    #[test]
+    fn nbs_round_trips_through_text_to_the_same_bytes() {
+        let bytes = writers::song(&sample_song()).unwrap();
+        let song = parsers::song(&bytes).unwrap();
+        let text = to_text(&song);
+        let back = from_text(&text).unwrap_or_else(|error| panic!("{}\n{}", error, text));
+        assert_eq!(back, song);
+        assert_eq!(writers::song(&back).unwrap(), bytes);
+        assert_eq!(back.noteblocks.ticks(..).map(|(tick, _)| tick).collect::<Vec<_>>(), vec![0, 4, 9, 12]);
+        assert_eq!(to_text(&back), text);
+    }
+
+    #[test]
+    fn negative_instruments_are_errors() {
+        let text = to_text(&sample_song()).replace("\n9 1 3 33 ", "\n9 1 -1 33 ");
+        let error = from_text(&text).unwrap_err();
+        assert_eq!(error, TextError { line: 0, message: "invalid song: expected an instrument below 17, got -1".to_owned() });
+    }
+
+    #[test]
+    fn unknown_sections_are_errors() {
+        let text = to_text(&sample_song());
+        assert!(from_text(&text).is_ok());
+
+        let mut lines: Vec<&str> = text.lines().collect();
+        let layers = lines.iter().position(|line| *line == "[layers]").unwrap();
+        lines.insert(layers, "[comments]");
+        let error = from_text(&lines.join("\n")).unwrap_err();
+        assert_eq!(error, TextError { line: layers + 1, message: "unknown section [comments]".to_owned() });
+    }
+}
@@ -0,0 +1,305 @@
+use std::fmt;
+
+use crate::parsers::{self, Expected, Header, Noteblock, Layer, Instrument, Song, NoteblockSection};
+use crate::playback;
+
+/// Why a song can't be stored as `.nbs`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WriteError {
+    /// The file stores the jump between ticks with notes in 16 bits.
+    TickJump { from: i32, to: i32 },
+    /// And the jump between layers of a tick.
+    LayerJump { tick: i32, from: i32, to: i32 },
+    /// At most 255 custom instruments fit.
+    CustomInstruments(usize),
+    /// Classic files start with the song length, and a 0 there marks open nbs files instead.
+    ClassicLength,
+    /// The song length is a short, the last tick has to fit.
+    SongLength(i32),
+    /// The song wouldn't parse again, see [`parsers::validate`].
+    Invalid(Expected),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::TickJump { from, to } => write!(f, "the jump from tick {} to tick {} is longer than the {} ticks a .nbs file can store", from, to, i16::MAX),
+            WriteError::LayerJump { tick, from, to } => write!(f, "the jump from layer {} to layer {} at tick {} is longer than the {} layers a .nbs file can store", from, to, tick, i16::MAX),
+            WriteError::CustomInstruments(count) => write!(f, "the song has {} custom instruments, a .nbs file holds at most {}", count, u8::MAX),
+            WriteError::ClassicLength => write!(f, "a classic .nbs file needs a note after tick 0, its song length can't be 0"),
+            WriteError::SongLength(tick) => write!(f, "the last tick {} is past the {} ticks a .nbs file can store", tick, i16::MAX),
+            WriteError::Invalid(expected) => write!(f, "invalid song: expected {}", expected),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+fn le_i8(output: &mut Vec<u8>, value: i8) {
+    output.extend_from_slice(&value.to_le_bytes());
+}
+fn le_u8(output: &mut Vec<u8>, value: u8) {
+    output.extend_from_slice(&value.to_le_bytes());
+}
+fn le_i16(output: &mut Vec<u8>, value: i16) {
+    output.extend_from_slice(&value.to_le_bytes());
+}
+fn le_i32(output: &mut Vec<u8>, value: i32) {
+    output.extend_from_slice(&value.to_le_bytes());
+}
+fn string(output: &mut Vec<u8>, value: &str) {
+    le_i32(output, value.len() as i32);
+    output.extend_from_slice(value.as_bytes());
+}
+
+pub fn header(output: &mut Vec<u8>, header: &Header) -> Result<(), WriteError> {
+    if header.open_nbs_version == 0 {
+        if header.song_length == 0 {
+            return Err(WriteError::ClassicLength);
+        }
+        le_i16(output, header.song_length); //classic files start with the song length
+    } else {
+        le_i16(output, 0); //0 marks the file as open nbs
+        le_i8(output, header.open_nbs_version);
+        le_i8(output, header.vanilla_instrument_count);
+        if header.open_nbs_version >= 3 {
+            le_i16(output, header.song_length);
+        }
+    }
+    le_i16(output, header.layer_count);
+    string(output, &header.name);
+    string(output, &header.author);
+    string(output, &header.orig_author);
+    string(output, &header.description);
+    le_i16(output, header.tempo);
+    le_i8(output, header.auto_save);
+    le_i8(output, header.auto_save_period);
+    le_i8(output, header.time_signature);
+    le_i32(output, header.minutes_spent);
+    le_i32(output, header.left_clicks);
+    le_i32(output, header.right_clicks);
+    le_i32(output, header.noteblocks_added);
+    le_i32(output, header.noteblocks_removed);
+    string(output, &header.original_file_name);
+    if header.open_nbs_version >= 4 {
+        le_i8(output, header.looping);
+        le_i8(output, header.loop_count);
+        le_i16(output, header.loop_start_tick);
+    }
+    Ok(())
+}
+
+pub fn noteblock(output: &mut Vec<u8>, noteblock: &Noteblock, version: i8) {
+    le_i8(output, noteblock.instrument);
+    le_i8(output, noteblock.key);
+    if version < 4 {
+        return;
+    }
+    le_i8(output, noteblock.volume);
+    le_u8(output, noteblock.panning);
+    le_i16(output, noteblock.pitch);
+}
+
+/* turns the absolute SetTick/SetLayer values back into the jumps the file stores */
+pub fn noteblocks(output: &mut Vec<u8>, noteblocks: &[NoteblockSection], version: i8) -> Result<(), WriteError> {
+    let mut tick: i32 = -1;
+    let mut layer_pos: i32 = -1;
+    let mut in_tick = false;
+    for section in noteblocks {
+        match section {
+            NoteblockSection::SetTick(num) => {
+                if in_tick {
+                    le_i16(output, 0); //end of the previous tick's layers
+                }
+                let jump = i16::try_from(num - tick).map_err(|_| WriteError::TickJump { from: tick, to: *num })?;
+                le_i16(output, jump);
+                tick = *num;
+                layer_pos = -1;
+                in_tick = true;
+            },
+            NoteblockSection::SetLayer(num) => {
+                let jump = i16::try_from(num - layer_pos).map_err(|_| WriteError::LayerJump { tick, from: layer_pos, to: *num })?;
+                le_i16(output, jump);
+                layer_pos = *num;
+            },
+            NoteblockSection::Noteblock(block) => noteblock(output, block, version),
+        }
+    }
+    if in_tick {
+        le_i16(output, 0);
+    }
+    le_i16(output, 0); //end of the noteblocks
+    Ok(())
+}
+
+pub fn layer(output: &mut Vec<u8>, layer: &Layer, version: i8) {
+    string(output, &layer.name);
+    if version >= 4 {
+        le_i8(output, layer.locked);
+    }
+    le_i8(output, layer.volume);
+    if version >= 2 {
+        le_u8(output, layer.stereo);
+    }
+}
+
+pub fn custom_instrument(output: &mut Vec<u8>, instrument: &Instrument) {
+    string(output, &instrument.name);
+    string(output, &instrument.sound_file);
+    le_i8(output, instrument.sound_key);
+    le_i8(output, instrument.press_key);
+}
+
+/// The song as `.nbs` bytes, checked with [`parsers::validate`] so they read back.
+///
+/// The song length is the last tick, whatever the header says, and a song
+/// without layers gets playback's default ones when custom instruments follow.
+pub fn song(song: &Song) -> Result<Vec<u8>, WriteError> {
+    parsers::validate(song).map_err(WriteError::Invalid)?;
+    let custom_instruments = u8::try_from(song.custom_instruments.len()).map_err(|_| WriteError::CustomInstruments(song.custom_instruments.len()))?;
+    let last_tick = song.noteblocks.last_tick().unwrap_or(0);
+    let song_length = i16::try_from(last_tick).map_err(|_| WriteError::SongLength(last_tick))?;
+    let mut output: Vec<u8> = Vec::new();
+    header(&mut output, &Header { song_length, ..song.header.clone() })?;
+    noteblocks(&mut output, &song.noteblocks.to_sections(), song.header.open_nbs_version)?;
+
+    //LAYERS (optional, the parser stops at the end of the file)
+    if song.layers.is_empty() && song.custom_instruments.is_empty() {
+        return Ok(output);
+    }
+    for layer_entry in &playback::layers(song) {
+        layer(&mut output, layer_entry, song.header.open_nbs_version);
+    }
+
+    //CUSTOM INSTRUMENTS
+    le_u8(&mut output, custom_instruments);
+    for instrument in &song.custom_instruments {
+        custom_instrument(&mut output, instrument);
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers;
+
+    /* a song using every field the version stores, the rest left the way the parser fills them in */
+    fn sample_song(version: i8) -> Song {
+        let mut song = Song {
+            header: Header {
+                open_nbs_version: version,
+                vanilla_instrument_count: if version == 0 { 10 } else { 16 },
+                song_length: 40,
+                layer_count: 3,
+                name: "Round trip".into(),
+                author: "someone".into(),
+                orig_author: "someone else".into(),
+                description: "every field set".into(),
+                tempo: 1000,
+                auto_save: 1,
+                auto_save_period: 10,
+                time_signature: 3,
+                minutes_spent: 12,
+                left_clicks: 345,
+                right_clicks: 67,
+                noteblocks_added: 89,
+                noteblocks_removed: 10,
+                original_file_name: "song.mid".into(),
+                looping: if version >= 4 { 1 } else { 0 },
+                loop_count: if version >= 4 { 2 } else { 0 },
+                loop_start_tick: if version >= 4 { 8 } else { 0 },
+            },
+            noteblocks: Default::default(),
+            layers: (0..3).map(|index| Layer {
+                name: format!("layer {}", index),
+                locked: if version >= 4 { index % 2 } else { 0 },
+                volume: 50 + index*10,
+                stereo: if version >= 2 { 60 + index as u8*20 } else { 100 },
+            }).collect(),
+            custom_instruments: vec![Instrument { name: "Custom".into(), sound_file: "custom.ogg".into(), sound_key: 50, press_key: 1 }],
+        };
+        let custom = song.header.vanilla_instrument_count;
+        for (tick, layer, instrument) in [(0, 0, 0), (0, 2, 3), (5, 1, custom), (17, 0, 4), (40, 2, 1)] {
+            song.noteblocks.insert(tick, layer, Noteblock {
+                instrument,
+                key: 33 + tick as i8 % 24,
+                volume: if version >= 4 { 30 + layer as i8*20 } else { 100 },
+                panning: if version >= 4 { 50 + layer as u8*50 } else { 100 },
+                pitch: if version >= 4 { tick as i16 * 3 - 20 } else { 0 },
+            });
+        }
+        song
+    }
+
+    fn song_bytes(song: &Song) -> Vec<u8> {
+        super::song(song).unwrap()
+    }
+
+    #[test]
+    fn round_trips_every_version() {
+        for version in 0..=5 {
+            let song = sample_song(version);
+            let bytes = song_bytes(&song);
+            let parsed = parsers::song(&bytes).unwrap_or_else(|error| panic!("version {}: {}", version, error));
+            assert_eq!(parsed, song, "version {}", version);
+            assert_eq!(song_bytes(&parsed), bytes, "version {}", version);
+        }
+    }
+
+    #[test]
+    fn long_jumps_are_errors() {
+        let mut output = Vec::new();
+        let far = 40 + i16::MAX as i32 + 1;
+        assert_eq!(noteblocks(&mut output, &[NoteblockSection::SetTick(40), NoteblockSection::SetTick(far)], 5), Err(WriteError::TickJump { from: 40, to: far }));
+        assert_eq!(noteblocks(&mut output, &[NoteblockSection::SetTick(1), NoteblockSection::SetLayer(i16::MAX as i32)], 5), Err(WriteError::LayerJump { tick: 1, from: -1, to: i16::MAX as i32 }));
+
+        //whole songs that far out are longer than the header can say
+        let mut song = sample_song(5);
+        song.noteblocks.insert(far, 0, Noteblock { instrument: 0, key: 45, volume: 100, panning: 100, pitch: 0 });
+        assert_eq!(super::song(&song), Err(WriteError::SongLength(far)));
+
+        //and layers that far out are past the layer count
+        let mut song = sample_song(5);
+        song.header.layer_count = i16::MAX;
+        song.layers.clear();
+        song.noteblocks.insert(1, i16::MAX as i32, Noteblock { instrument: 0, key: 45, volume: 100, panning: 100, pitch: 0 });
+        assert_eq!(super::song(&song), Err(WriteError::Invalid(Expected::Layer { index: i16::MAX as i32, layer_count: i16::MAX })));
+    }
+
+    #[test]
+    fn song_length_comes_from_the_last_tick() {
+        for version in [0, 3, 5] {
+            let mut song = sample_song(version);
+            song.header.song_length = 0;
+            song.noteblocks.insert(500, 1, Noteblock { instrument: 0, key: 45, volume: 100, panning: 100, pitch: 0 });
+            let parsed = parsers::song(&song_bytes(&song)).unwrap_or_else(|error| panic!("version {}: {}", version, error));
+            assert_eq!(parsed.header.song_length, 500, "version {}", version);
+        }
+    }
+
+    #[test]
+    fn classic_songs_need_a_length() {
+        let mut song = sample_song(0);
+        song.noteblocks = Default::default();
+        assert_eq!(super::song(&song), Err(WriteError::ClassicLength));
+        song.noteblocks.insert(0, 0, Noteblock { instrument: 0, key: 45, volume: 100, panning: 100, pitch: 0 });
+        assert_eq!(super::song(&song), Err(WriteError::ClassicLength));
+    }
+
+    #[test]
+    fn missing_layers_are_written_as_defaults() {
+        let mut song = sample_song(5);
+        song.layers.clear();
+        let parsed = parsers::song(&song_bytes(&song)).unwrap();
+        assert_eq!(parsed.layers, playback::layers(&song));
+        assert_eq!(parsed.custom_instruments, song.custom_instruments);
+    }
+
+    #[test]
+    fn invalid_songs_are_errors() {
+        let mut song = sample_song(5);
+        song.noteblocks.insert(3, 0, Noteblock { instrument: -1, key: 45, volume: 100, panning: 100, pitch: 0 });
+        assert_eq!(super::song(&song), Err(WriteError::Invalid(Expected::Instrument { index: -1, instrument_count: 17 })));
+    }
+}